@@ -0,0 +1,265 @@
+use crate::channel::{ChannelConfig, ClientAddressHeader};
+use async_std::io::{ReadExt, WriteExt};
+use async_std::net::TcpStream;
+use socket2::{Domain, Protocol, SockRef, Socket, TcpKeepalive, Type};
+use std::fmt;
+use std::future::Future;
+use std::io;
+use std::net::Shutdown;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::time::Duration;
+
+#[cfg(target_os = "linux")]
+const LIVENESS_PROBES: u32 = 3;
+
+const READY_PROBE_TIMEOUT: Duration = Duration::from_secs(5);
+
+pub type ConnectFuture<R = TcpStream> =
+    Pin<Box<dyn Future<Output = io::Result<R>> + Send + 'static>>;
+
+type ConnectFn<R> = dyn Fn(SocketAddr, &ChannelConfig) -> ConnectFuture<R> + Send + Sync;
+
+/// Function making a raw connection to the real server at the given address.
+///
+/// The default one makes TCP connections, applying the TCP specific options of the config.
+pub struct Connector<R>(Arc<ConnectFn<R>>);
+impl<R> Connector<R> {
+    /// Makes a new `Connector` instance.
+    pub fn new<F>(f: F) -> Self
+    where
+        F: Fn(SocketAddr, &ChannelConfig) -> ConnectFuture<R> + Send + Sync + 'static,
+    {
+        Connector(Arc::new(f))
+    }
+}
+impl Default for Connector<TcpStream> {
+    fn default() -> Self {
+        Connector::new(connect_tcp)
+    }
+}
+impl<R> Clone for Connector<R> {
+    fn clone(&self) -> Self {
+        Connector(self.0.clone())
+    }
+}
+impl<R> fmt::Debug for Connector<R> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Connector {{ .. }}")
+    }
+}
+
+/// Stream connecting a proxy channel and a real server.
+pub(crate) trait BackendStream:
+    async_std::io::Read + async_std::io::Write + Unpin + Send + 'static
+{
+    /// Shuts down the write side of the stream.
+    fn shutdown_write(&self) -> io::Result<()>;
+
+    /// Sets `TCP_NODELAY` if the stream is a TCP stream.
+    fn set_nodelay(&self, _nodelay: bool) -> io::Result<()> {
+        Ok(())
+    }
+}
+impl BackendStream for TcpStream {
+    fn shutdown_write(&self) -> io::Result<()> {
+        self.shutdown(Shutdown::Write)
+    }
+
+    fn set_nodelay(&self, nodelay: bool) -> io::Result<()> {
+        TcpStream::set_nodelay(self, nodelay)
+    }
+}
+
+fn connect_tcp(addr: SocketAddr, config: &ChannelConfig) -> ConnectFuture {
+    let future: ConnectFuture = match config.backend_interface.clone() {
+        None => Box::pin(TcpStream::connect(addr)),
+        Some(interface) => Box::pin(async_std::task::spawn_blocking(move || {
+            let socket = bound_socket(addr, &interface)?;
+            socket.connect(&addr.into())?;
+            Ok(TcpStream::from(std::net::TcpStream::from(socket)))
+        })),
+    };
+    match config.backend_liveness_interval {
+        None => future,
+        Some(interval) => Box::pin(async move {
+            let stream = future.await?;
+            set_liveness_probe(&stream, interval)?;
+            Ok(stream)
+        }),
+    }
+}
+
+/// Starts connecting to the real server through `connector`.
+///
+/// `path` is the request target of the client's handshake request, and `client` is the pair of
+/// the client's address and the address to which it connected (if known).
+pub fn connect<R: BackendStream>(
+    connector: &Connector<R>,
+    addr: SocketAddr,
+    config: &ChannelConfig,
+    path: &str,
+    client: Option<(SocketAddr, SocketAddr)>,
+) -> ConnectFuture<R> {
+    let future = (connector.0)(addr, config);
+    // The header has to precede any other data, including the ready probe
+    let future: ConnectFuture<R> = match config.client_address_header {
+        ClientAddressHeader::None => future,
+        ClientAddressHeader::ProxyV1 => {
+            let line = proxy_v1_line(client);
+            Box::pin(async move {
+                let mut stream = future.await?;
+                stream.write_all(line.as_bytes()).await?;
+                Ok(stream)
+            })
+        }
+    };
+    let future: ConnectFuture<R> = match config.backend_ready_probe.clone() {
+        None => future,
+        Some((probe, expected)) => Box::pin(async move {
+            let mut stream = future.await?;
+            let check = check_ready(&mut stream, &probe, &expected);
+            async_std::io::timeout(READY_PROBE_TIMEOUT, check).await?;
+            Ok(stream)
+        }),
+    };
+    if config.send_path_header {
+        let line = format!("Path: {}\r\n", path);
+        Box::pin(async move {
+            let mut stream = future.await?;
+            stream.write_all(line.as_bytes()).await?;
+            Ok(stream)
+        })
+    } else {
+        future
+    }
+}
+
+/// Returns a PROXY protocol version 1 header line for `client`.
+fn proxy_v1_line(client: Option<(SocketAddr, SocketAddr)>) -> String {
+    match client {
+        Some((src, dst)) if src.is_ipv4() == dst.is_ipv4() => {
+            let family = if src.is_ipv4() { "TCP4" } else { "TCP6" };
+            format!(
+                "PROXY {} {} {} {} {}\r\n",
+                family,
+                src.ip(),
+                dst.ip(),
+                src.port(),
+                dst.port()
+            )
+        }
+        _ => "PROXY UNKNOWN\r\n".to_owned(),
+    }
+}
+
+/// Sends `probe` and checks that the real server responds with `expected`.
+///
+/// Only `expected.len()` bytes are read, so any data following the response is left for relaying.
+async fn check_ready<R: BackendStream>(
+    stream: &mut R,
+    probe: &[u8],
+    expected: &[u8],
+) -> io::Result<()> {
+    stream.write_all(probe).await?;
+    let mut response = vec![0; expected.len()];
+    stream.read_exact(&mut response).await?;
+    if response != expected {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("Unexpected ready probe response: {:?}", response),
+        ));
+    }
+    Ok(())
+}
+
+/// Enables TCP keepalive probes (and `TCP_USER_TIMEOUT` on Linux) so that a silently dead real
+/// server makes socket operations fail with `TimedOut`.
+fn set_liveness_probe(stream: &TcpStream, interval: Duration) -> io::Result<()> {
+    let socket = SockRef::from(stream);
+    let keepalive = TcpKeepalive::new().with_time(interval);
+    #[cfg(target_os = "linux")]
+    let keepalive = keepalive
+        .with_interval(interval)
+        .with_retries(LIVENESS_PROBES);
+    socket.set_tcp_keepalive(&keepalive)?;
+    #[cfg(target_os = "linux")]
+    socket.set_tcp_user_timeout(Some(interval * (LIVENESS_PROBES + 1)))?;
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+fn bound_socket(addr: SocketAddr, interface: &str) -> io::Result<Socket> {
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+    socket.bind_device(Some(interface.as_bytes()))?;
+    Ok(socket)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn bound_socket(_addr: SocketAddr, _interface: &str) -> io::Result<Socket> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "Binding to an interface is only supported on Linux",
+    ))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn bound_socket_works() {
+        let addr = "127.0.0.1:80".parse().unwrap();
+        let socket = bound_socket(addr, "lo").unwrap();
+        assert_eq!(socket.device().unwrap(), Some(b"lo".to_vec()));
+    }
+
+    #[test]
+    fn proxy_v1_line_works() {
+        let v4 = |s: &str| s.parse::<SocketAddr>().unwrap();
+        assert_eq!(
+            proxy_v1_line(Some((v4("192.0.2.1:51234"), v4("192.0.2.2:80")))),
+            "PROXY TCP4 192.0.2.1 192.0.2.2 51234 80\r\n"
+        );
+        assert_eq!(
+            proxy_v1_line(Some((v4("[2001:db8::1]:51234"), v4("[2001:db8::2]:443")))),
+            "PROXY TCP6 2001:db8::1 2001:db8::2 51234 443\r\n"
+        );
+        assert_eq!(
+            proxy_v1_line(Some((v4("192.0.2.1:51234"), v4("[2001:db8::2]:443")))),
+            "PROXY UNKNOWN\r\n"
+        );
+        assert_eq!(proxy_v1_line(None), "PROXY UNKNOWN\r\n");
+    }
+
+    #[test]
+    fn liveness_probe_works() {
+        async_std::task::block_on(async {
+            let listener = async_std::net::TcpListener::bind("127.0.0.1:0")
+                .await
+                .unwrap();
+            let config = ChannelConfig {
+                backend_liveness_interval: Some(Duration::from_secs(5)),
+                ..Default::default()
+            };
+            let stream = connect_tcp(listener.local_addr().unwrap(), &config)
+                .await
+                .unwrap();
+
+            let socket = SockRef::from(&stream);
+            assert!(socket.keepalive().unwrap());
+            assert_eq!(socket.keepalive_time().unwrap(), Duration::from_secs(5));
+            #[cfg(target_os = "linux")]
+            {
+                assert_eq!(socket.keepalive_interval().unwrap(), Duration::from_secs(5));
+                assert_eq!(socket.keepalive_retries().unwrap(), LIVENESS_PROBES);
+                assert_eq!(
+                    socket.tcp_user_timeout().unwrap(),
+                    Some(Duration::from_secs(20))
+                );
+            }
+        });
+    }
+}
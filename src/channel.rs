@@ -1,46 +1,635 @@
+use crate::backend::{self, BackendStream, ConnectFuture, Connector};
+use crate::cidr::IpCidr;
 use crate::frame::{Frame, FrameDecoder, FrameEncoder};
-use crate::util::{self, WebSocketKey};
+use crate::observer::{ChannelObserver, Decision};
+use crate::opcode::Opcode;
+use crate::registry::{Registration, Registry};
+use crate::resume::ParkedBackends;
+use crate::route::{self, Route};
+use crate::util::{self, Timer, WebSocketKey};
+use crate::validator::Violation;
+use crate::webhook::{CloseEvent, CloseWebhook};
 use crate::{Error, ErrorKind, Result};
 use async_std::net::TcpStream;
+use bytecodec::bytes::BytesEncoder;
 use bytecodec::io::{IoDecodeExt, IoEncodeExt, ReadBuf, StreamState, WriteBuf};
+use bytecodec::tuple::TupleEncoder;
 use bytecodec::{Decode, Encode, EncodeExt};
 use httpcodec::{
     HeaderField, HttpVersion, NoBodyDecoder, NoBodyEncoder, ReasonPhrase, Request, RequestDecoder,
     Response, ResponseEncoder, StatusCode,
 };
+use regex::bytes::Regex;
+use std::fmt;
 use std::future::Future;
+use std::io;
 use std::mem;
 use std::net::SocketAddr;
 use std::pin::Pin;
+use std::sync::Arc;
 use std::task::Context;
 use std::task::Poll;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use trackable::error::ErrorKindExt;
 
-const BUF_SIZE: usize = 4096;
+const DEFAULT_BUF_SIZE: usize = 4096;
+const DEFAULT_RESUME_WINDOW: Duration = Duration::from_secs(30);
+
+/// Options applied to each proxy channel.
+#[derive(Debug, Clone, Default)]
+pub struct ChannelConfig {
+    /// Value of the `Server` header added to handshake responses.
+    ///
+    /// If `None`, the header is omitted.
+    pub server_header: Option<String>,
+
+    /// Whether to add a `Date` header to handshake responses.
+    pub emit_date: bool,
+
+    /// Type of the frames used to relay data from the real server to the client.
+    pub frame_type: FrameType,
+
+    /// Body of the `503 Service Unavailable` response sent when the real server cannot be reached.
+    ///
+    /// If `None`, the response has an empty body.
+    pub unavailable_body: Option<String>,
+
+    /// Name of the network interface that connections to the real server are bound to
+    /// (`SO_BINDTODEVICE`, Linux only).
+    pub backend_interface: Option<String>,
+
+    /// Format of the per-connection access log.
+    pub log_format: LogFormat,
+
+    /// Size of each buffer of a channel (the read and write buffers of the client stream and the
+    /// payload buffers of the frame encoder and decoder).
+    ///
+    /// Larger buffers relay bulk data with fewer system calls, while smaller ones save memory per
+    /// connection. Frame buffers are at least 125 bytes. If `None`, 4096 bytes are used.
+    pub buffer_size: Option<usize>,
+
+    /// Maximum payload size of the frames used to relay data from the real server to the client.
+    ///
+    /// If `None`, frames are only bounded by the buffer size.
+    pub max_outgoing_frame_size: Option<usize>,
+
+    /// Time window for coalescing outgoing WebSocket writes.
+    ///
+    /// If set, relayed frames are buffered for up to this duration (or until the write buffer
+    /// becomes full) before being written to the client, which reduces the number of small
+    /// TCP segments.
+    pub write_coalescing_window: Option<Duration>,
+
+    /// Maximum time from accepting a connection to relaying its first data byte.
+    ///
+    /// If exceeded, the connection is closed with a 503 response (while connecting to the real
+    /// server) or a Close frame with code 1008 (after the handshake).
+    pub time_to_first_byte_budget: Option<Duration>,
+
+    /// Pattern that the first data frame sent by the client (an application-level handshake)
+    /// must match before the real server is connected.
+    ///
+    /// If set, the handshake response is sent without waiting for the real server.
+    /// The first data frame is consumed by the proxy (it is not relayed) and the connection is
+    /// closed with code 1008 if the frame does not match (or 1011 if the real server cannot be
+    /// reached afterwards).
+    pub app_handshake_pattern: Option<Regex>,
+
+    /// Name of the request header carrying a session id.
+    ///
+    /// If set, only one channel is kept per session id: when a new connection arrives with
+    /// the id of an active channel, the older channel is closed.
+    pub session_header: Option<String>,
+
+    /// Close code sent to a channel replaced by a newer one with the same session id.
+    ///
+    /// It must be valid on the wire (e.g., not 1005 or 1006), which is checked by
+    /// `ProxyServer::with_config`. If `None`, 4000 is used.
+    pub session_close_code: Option<u16>,
+
+    /// URL (`http://HOST[:PORT][/PATH]`) that a JSON summary of each closed channel is posted to.
+    ///
+    /// Events are posted in the background; if the webhook is slow, excess events are dropped.
+    pub close_webhook: Option<String>,
+
+    /// Name of the request header carrying an absolute deadline of the connection
+    /// (seconds since the Unix epoch).
+    ///
+    /// If set and the header is present, the connection is closed with code 1001 at the deadline.
+    /// Requests with a malformed value are rejected with `400 Bad Request`.
+    pub deadline_header: Option<String>,
+
+    /// Idle time after which TCP keepalive probes are sent to the real server.
+    ///
+    /// If the real server stops responding to the probes, the connection is closed with
+    /// code 1011. If `None`, the OS defaults are used.
+    pub backend_liveness_interval: Option<Duration>,
+
+    /// Maximum time to wait for the closing handshake to complete.
+    ///
+    /// If exceeded (e.g. the Close frame cannot be flushed to an unresponsive client, or the
+    /// client never replies to it), the TCP connection is dropped.
+    pub close_timeout: Option<Duration>,
+
+    /// Probe bytes sent to the real server right after connecting, and the response that
+    /// the real server has to send back before the handshake succeeds.
+    ///
+    /// If the response differs or does not arrive in time, the handshake fails with
+    /// `503 Service Unavailable`. The probe and its response are not relayed.
+    pub backend_ready_probe: Option<(Vec<u8>, Vec<u8>)>,
+
+    /// Maximum number of bytes read from the client per poll.
+    ///
+    /// If the limit is reached, the channel yields to other tasks after processing the bytes read
+    /// so far. If `None`, the channel reads as long as data is available and the buffer has room.
+    pub max_read_per_poll: Option<usize>,
+
+    /// Whether to send the request target of the handshake request to the real server as a
+    /// `Path: <TARGET>\r\n` line before relaying any data.
+    pub send_path_header: bool,
+
+    /// Header carrying the client's address sent to the real server before any other data.
+    pub client_address_header: ClientAddressHeader,
+
+    /// Whether to mask the frames sent to clients.
+    ///
+    /// This violates RFC 6455 (servers must not mask frames) and is only intended for
+    /// reproducing and diagnosing client bugs.
+    pub mask_outgoing_frames: bool,
+
+    /// Name of the request header carrying a resume token.
+    ///
+    /// If set, the real server connection of a channel whose client disappears without
+    /// the closing handshake is kept for `resume_window`, and a client that reconnects with the
+    /// same token is relayed to that connection instead of a new one. Data already read from
+    /// either side but not delivered yet is lost. Anyone knowing the token can take over the
+    /// connection, so tokens should be unguessable.
+    pub resume_header: Option<String>,
+
+    /// How long the real server connection of a disappeared client is kept for resumption.
+    ///
+    /// If `None`, 30 seconds is used.
+    pub resume_window: Option<Duration>,
+
+    /// Types of the data frames accepted from clients.
+    ///
+    /// If a client sends a data frame of another type, the connection is closed with code 1003
+    /// and the frame is not relayed.
+    pub accepted_frame_types: AcceptedFrameTypes,
+
+    /// Payload of the application-level heartbeat frames sent to clients, and the interval
+    /// between them.
+    ///
+    /// A heartbeat is a data frame (of the type given by `frame_type`) that is sent when nothing
+    /// has been relayed to the client for the interval. It is not sent to the real server.
+    /// The payload must be at most `buffer_size` bytes.
+    pub client_heartbeat: Option<(Vec<u8>, Duration)>,
+
+    /// Maximum length of the request target of handshake requests.
+    ///
+    /// Requests with a longer target are rejected with `414 URI Too Long`.
+    pub max_uri_length: Option<usize>,
+
+    /// Whether to only validate the frames sent by clients instead of relaying them.
+    ///
+    /// In this mode, the real server is never connected, frames are checked against RFC 6455
+    /// (masking, reserved bits, control frames, fragmentation, UTF-8 and close codes), their
+    /// payloads are discarded, and a violation closes the connection with code 1002 (or 1007
+    /// for invalid UTF-8).
+    pub validate_only: bool,
+
+    /// Callback computing additional headers of the `101 Switching Protocols` response from the
+    /// handshake request.
+    ///
+    /// It is called only for accepted requests, right before the response is made (i.e., after
+    /// the real server has been connected, unless the response is sent first).
+    /// Headers with an invalid name or value are skipped.
+    pub response_header_hook: Option<ResponseHeaderHook>,
+
+    /// Headers added to every `101 Switching Protocols` response.
+    ///
+    /// Names and values are validated by `ProxyServer::with_config`.
+    pub extra_response_headers: Vec<(String, String)>,
+
+    /// Observer notified of the handshake, opening and closing of each channel.
+    pub observer: Option<Arc<dyn ChannelObserver>>,
+
+    /// How long client data may keep waiting for the real server to accept it before a warning
+    /// is logged.
+    ///
+    /// The warning is logged once per episode; a new episode starts after the pending data has
+    /// been written.
+    pub backend_lag_warning: Option<Duration>,
+
+    /// Maximum total payload size of a (possibly fragmented) message sent by a client.
+    ///
+    /// A message exceeding it closes the connection with code 1009 (Message Too Big).
+    pub max_message_size: Option<usize>,
+
+    /// Interval between the Ping frames sent to clients.
+    ///
+    /// If no Pong frame has been received by the time the next Ping is due, the connection is
+    /// closed with code 1001 (Going Away).
+    pub keepalive_interval: Option<Duration>,
+
+    /// Client address ranges allowed to connect.
+    ///
+    /// If empty, every client that is not denied by `deny_cidrs` is allowed.
+    pub allow_cidrs: Vec<IpCidr>,
+
+    /// Client address ranges refused at accept time (takes precedence over `allow_cidrs`).
+    pub deny_cidrs: Vec<IpCidr>,
+
+    /// How long a channel may go without reading anything from either the client or the real
+    /// server.
+    ///
+    /// When it elapses, the connection is closed with code 1001 (Going Away).
+    pub idle_timeout: Option<Duration>,
+
+    /// Budget of the time spent polling a channel, and the window over which it is accumulated.
+    ///
+    /// A channel exceeding the budget is logged once per window (e.g., a client flooding tiny
+    /// frames).
+    pub poll_time_budget: Option<(Duration, Duration)>,
+
+    /// Whether to close channels exceeding `poll_time_budget` with code 1008 (Policy Violation).
+    pub close_over_poll_time_budget: bool,
+
+    /// Whether the handshake response is sent before or after the real server is connected.
+    pub handshake_order: HandshakeOrder,
+
+    /// Maximum number of Pong frames sent to a client; further Ping frames are ignored.
+    pub max_pongs: Option<u64>,
+
+    /// Whether to close the connection with code 1008 (Policy Violation) when a Ping frame
+    /// arrives after `max_pongs` Pong frames have been sent.
+    pub close_over_max_pongs: bool,
+
+    /// Payload of the data frame (of the type given by `frame_type`) sent to a client right
+    /// before the Close frame when its message exceeds `max_message_size`.
+    ///
+    /// The payload must be at most `buffer_size` bytes.
+    pub overflow_notice: Option<Vec<u8>>,
+
+    /// Subprotocols that may be selected through the `Sec-WebSocket-Protocol` header.
+    ///
+    /// The first protocol offered by the client that is in this list is selected and echoed in
+    /// the handshake response. If empty, the header is ignored.
+    pub subprotocols: Vec<String>,
+
+    /// Whether to reject handshake requests offering only protocols not in `subprotocols`
+    /// (with `400 Bad Request`).
+    ///
+    /// Requests without the `Sec-WebSocket-Protocol` header are accepted regardless.
+    pub require_subprotocol: bool,
+
+    /// Origins from which handshake requests are accepted (e.g., `https://example.com`).
+    ///
+    /// A `*` in an entry matches any sequence of characters (e.g., `https://*.example.com`, or
+    /// `*` for any origin). If not empty, requests whose `Origin` header is missing or matches no
+    /// entry are rejected with `403 Forbidden`.
+    pub allowed_origins: Vec<String>,
+
+    /// Logs the lifecycle events (creation, handshake and termination) of only one in this many
+    /// connections at the info level; those of the others are logged at the debug level.
+    ///
+    /// Warnings and errors are logged regardless. If `None`, every connection is logged.
+    pub log_sample_rate: Option<u64>,
+
+    /// Routes choosing the real server by the request target of the handshake request.
+    ///
+    /// The route with the longest matching prefix is taken. If not empty, the real server address
+    /// given to the channel is not used, and requests matching no route are rejected with
+    /// `404 Not Found` (a `/` route can serve as the fallback).
+    pub routes: Vec<Route>,
+}
+impl ChannelConfig {
+    /// Returns the size of the buffers of a channel (see `buffer_size`).
+    pub(crate) fn buffer_size(&self) -> usize {
+        self.buffer_size.unwrap_or(DEFAULT_BUF_SIZE)
+    }
+
+    /// Returns the level of the lifecycle logs of the connection `conn_id` (see
+    /// `log_sample_rate`).
+    pub(crate) fn lifecycle_log_level(&self, conn_id: u64) -> log::Level {
+        match self.log_sample_rate {
+            Some(n) if n > 1 && !conn_id.is_multiple_of(n) => log::Level::Debug,
+            _ => log::Level::Info,
+        }
+    }
+}
+
+type ResponseHeaderFn = dyn Fn(&Request<()>) -> Vec<(String, String)> + Send + Sync;
+
+/// Callback computing response headers from a handshake request (see
+/// `ChannelConfig::response_header_hook`).
+#[derive(Clone)]
+pub struct ResponseHeaderHook(Arc<ResponseHeaderFn>);
+impl ResponseHeaderHook {
+    /// Makes a new `ResponseHeaderHook` instance.
+    pub fn new<F>(f: F) -> Self
+    where
+        F: Fn(&Request<()>) -> Vec<(String, String)> + Send + Sync + 'static,
+    {
+        ResponseHeaderHook(Arc::new(f))
+    }
+
+    fn call(&self, request: &Request<()>) -> Vec<(String, String)> {
+        (self.0)(request)
+    }
+}
+impl fmt::Debug for ResponseHeaderHook {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "ResponseHeaderHook {{ .. }}")
+    }
+}
+
+/// Reason why a WebSocket handshake request was rejected.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HandshakeRejection {
+    /// The method is not `GET`.
+    BadMethod,
+
+    /// The HTTP version is not 1.1.
+    BadVersion,
+
+    /// The `Upgrade` header is not `websocket`.
+    BadUpgrade,
+
+    /// The request is a plain HTTP request without the `Upgrade` header.
+    UpgradeRequired,
+
+    /// The `Connection` header does not contain `Upgrade`.
+    BadConnection,
+
+    /// The `Sec-WebSocket-Version` header is not `13`.
+    BadWebSocketVersion,
+
+    /// The `Sec-WebSocket-Key` header is missing.
+    MissingKey,
+
+    /// The deadline header (see `ChannelConfig::deadline_header`) is malformed.
+    BadDeadline,
+
+    /// The request target exceeds `ChannelConfig::max_uri_length`.
+    UriTooLong,
+
+    /// None of the offered subprotocols is in `ChannelConfig::subprotocols` (see
+    /// `ChannelConfig::require_subprotocol`).
+    UnsupportedSubprotocol,
+
+    /// The `Origin` header is missing or not in `ChannelConfig::allowed_origins`.
+    ForbiddenOrigin,
+
+    /// The request target matches no route in `ChannelConfig::routes`.
+    NoRoute,
+
+    /// `ChannelConfig::observer` rejected the request with the status code.
+    Observer(u16),
+}
+impl std::error::Error for HandshakeRejection {}
+impl fmt::Display for HandshakeRejection {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let reason = match self {
+            HandshakeRejection::BadMethod => "method is not GET",
+            HandshakeRejection::BadVersion => "HTTP version is not 1.1",
+            HandshakeRejection::BadUpgrade => "`Upgrade` is not `websocket`",
+            HandshakeRejection::UpgradeRequired => "`Upgrade` is missing",
+            HandshakeRejection::BadConnection => "`Connection` does not contain `Upgrade`",
+            HandshakeRejection::BadWebSocketVersion => "`Sec-WebSocket-Version` is not 13",
+            HandshakeRejection::MissingKey => "`Sec-WebSocket-Key` is missing",
+            HandshakeRejection::BadDeadline => "deadline header is malformed",
+            HandshakeRejection::UriTooLong => "request target is too long",
+            HandshakeRejection::UnsupportedSubprotocol => "no offered subprotocol is supported",
+            HandshakeRejection::ForbiddenOrigin => "`Origin` is not allowed",
+            HandshakeRejection::NoRoute => "no route matches the request target",
+            HandshakeRejection::Observer(status) => {
+                return write!(f, "rejected by the observer with {}", status);
+            }
+        };
+        f.write_str(reason)
+    }
+}
+
+/// Format of the per-connection access log.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum LogFormat {
+    /// No access log is emitted.
+    #[default]
+    Plain,
+
+    /// An access log line in Common Log Format is emitted when a channel terminates.
+    ///
+    /// The line is logged at the info level with the `wstcp::access` target.
+    /// Its status is that of the handshake response and its size is the number of relayed bytes.
+    Clf,
+}
+
+/// Order of answering a handshake request and connecting to the real server.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum HandshakeOrder {
+    /// The real server is connected first; if it cannot be reached, the request is answered
+    /// with `503 Service Unavailable`.
+    #[default]
+    BackendFirst,
+
+    /// The `101 Switching Protocols` response is sent immediately and the real server is
+    /// connected afterwards; if it cannot be reached, the connection is closed with code 1011.
+    RespondFirst,
+}
+
+/// Type of the WebSocket frames sent to clients.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum FrameType {
+    /// Binary frames.
+    #[default]
+    Binary,
+
+    /// Text frames.
+    Text,
+
+    /// The same type as the last data frame sent by the client (binary until the client sends one).
+    Mirror,
+}
+
+/// Header carrying the client's address that is sent to the real server after connecting.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ClientAddressHeader {
+    /// No header is sent.
+    #[default]
+    None,
+
+    /// PROXY protocol version 1 header (e.g., `PROXY TCP4 192.0.2.1 192.0.2.2 51234 80\r\n`).
+    ProxyV1,
+}
+
+/// Types of the WebSocket data frames accepted from clients.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum AcceptedFrameTypes {
+    /// Both binary and text frames.
+    #[default]
+    Any,
+
+    /// Binary frames only.
+    Binary,
+
+    /// Text frames only.
+    Text,
+}
+impl AcceptedFrameTypes {
+    fn accepts(self, opcode: Opcode) -> bool {
+        match self {
+            AcceptedFrameTypes::Any => true,
+            AcceptedFrameTypes::Binary => opcode == Opcode::BinaryFrame,
+            AcceptedFrameTypes::Text => opcode == Opcode::TextFrame,
+        }
+    }
+}
+
+/// Stream connecting a proxy channel and a client.
+pub(crate) trait ClientStream: async_std::io::Read + async_std::io::Write + Unpin {
+    /// Returns the address of the client.
+    fn peer_addr(&self) -> io::Result<SocketAddr>;
+
+    /// Returns the address to which the client connected.
+    fn local_addr(&self) -> io::Result<SocketAddr>;
+
+    /// Sets `TCP_NODELAY` if the stream is a TCP stream.
+    fn set_nodelay(&self, _nodelay: bool) -> io::Result<()> {
+        Ok(())
+    }
+}
+impl ClientStream for TcpStream {
+    fn peer_addr(&self) -> io::Result<SocketAddr> {
+        TcpStream::peer_addr(self)
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        TcpStream::local_addr(self)
+    }
+
+    fn set_nodelay(&self, nodelay: bool) -> io::Result<()> {
+        TcpStream::set_nodelay(self, nodelay)
+    }
+}
 
 #[derive(Debug)]
-pub struct ProxyChannel {
-    ws_stream: TcpStream,
+pub struct ProxyChannel<S: ClientStream = TcpStream, R: BackendStream = TcpStream> {
+    ws_stream: S,
     ws_rbuf: ReadBuf<Vec<u8>>,
     ws_wbuf: WriteBuf<Vec<u8>>,
     real_server_addr: SocketAddr,
-    real_stream: Option<TcpStream>,
+    real_stream: Option<R>,
     real_stream_rstate: StreamState,
     real_stream_wstate: StreamState,
-    handshake: Handshake,
+    handshake: Handshake<R>,
     closing: Closing,
     pending_pong: Option<Vec<u8>>,
+    pongs: u64,
+    pending_heartbeat: bool,
+    pending_notice: bool,
+    pending_ping: bool,
     pending_close: Option<Frame>,
     frame_decoder: FrameDecoder,
     frame_encoder: FrameEncoder,
+    config: ChannelConfig,
+    terminated: bool,
+    started_at: SystemTime,
+    request_line: Option<String>,
+    request_path: Option<String>,
+    response_headers: Vec<(String, String)>,
+    response_status: Option<u16>,
+    coalescing_timer: Option<Timer>,
+    first_byte_timer: Option<Timer>,
+    app_handshake: Option<AppHandshake<R>>,
+    sessions: Registry<String>,
+    session: Option<Registration<String>>,
+    close_webhook: Option<CloseWebhook>,
+    connection: Registration<u64>,
+    parked: ParkedBackends<R>,
+    connector: Connector<R>,
+    resume_token: Option<String>,
+    close_code: Option<u16>,
+    deadline: Option<SystemTime>,
+    deadline_timer: Option<Timer>,
+    close_timer: Option<Timer>,
+    heartbeat_timer: Option<Timer>,
+    heartbeat_mark: u64,
+    keepalive_timer: Option<Timer>,
+    awaiting_pong: bool,
+    idle_timer: Option<Timer>,
+    idle_mark: u64,
+    poll_window_start: Instant,
+    poll_time: Duration,
+    over_poll_time_budget: bool,
+    lag_timer: Option<Timer>,
+    lag_warned: bool,
+    read_budget: usize,
+    over_capacity: bool,
+    half_closed: bool,
+    real_write_shut: bool,
+    log_level: log::Level,
+    handshake_error: Option<Error>,
+}
+impl<S: ClientStream> ProxyChannel<S> {
+    pub fn new(
+        ws_stream: S,
+        real_server_addr: SocketAddr,
+        config: ChannelConfig,
+        sessions: Registry<String>,
+        close_webhook: Option<CloseWebhook>,
+        connection: Registration<u64>,
+        parked: ParkedBackends,
+    ) -> Self {
+        Self::with_connector(
+            ws_stream,
+            real_server_addr,
+            config,
+            sessions,
+            close_webhook,
+            connection,
+            parked,
+            Connector::default(),
+        )
+    }
 }
-impl ProxyChannel {
-    pub fn new(ws_stream: TcpStream, real_server_addr: SocketAddr) -> Self {
+impl<S: ClientStream, R: BackendStream> ProxyChannel<S, R> {
+    /// Makes a new `ProxyChannel` instance whose real server connection is made by `connector`.
+    #[allow(clippy::too_many_arguments)]
+    pub fn with_connector(
+        ws_stream: S,
+        real_server_addr: SocketAddr,
+        config: ChannelConfig,
+        sessions: Registry<String>,
+        close_webhook: Option<CloseWebhook>,
+        connection: Registration<u64>,
+        parked: ParkedBackends<R>,
+        connector: Connector<R>,
+    ) -> Self {
         let _ = ws_stream.set_nodelay(true);
-        log::info!("New proxy channel is created");
+        let log_level = config.lifecycle_log_level(*connection.key());
+        log::log!(
+            log_level,
+            "New proxy channel is created: conn_id={}",
+            connection.key()
+        );
+        let buffer_size = config.buffer_size();
+        let mut frame_encoder = FrameEncoder::with_buffer_size(buffer_size);
+        if let Some(size) = config.max_outgoing_frame_size {
+            frame_encoder.set_max_data_size(size);
+        }
+        frame_encoder.set_mask_outgoing(config.mask_outgoing_frames);
+        let mut frame_decoder = FrameDecoder::with_buffer_size(buffer_size);
+        if config.validate_only {
+            frame_decoder.enable_validation();
+        }
+        if let Some(size) = config.max_message_size {
+            frame_decoder.set_max_message_size(size as u64);
+        }
         ProxyChannel {
             ws_stream,
-            ws_rbuf: ReadBuf::new(vec![0; BUF_SIZE]),
-            ws_wbuf: WriteBuf::new(vec![0; BUF_SIZE]),
+            ws_rbuf: ReadBuf::new(vec![0; buffer_size]),
+            ws_wbuf: WriteBuf::new(vec![0; buffer_size]),
             real_server_addr,
             real_stream: None,
             real_stream_rstate: StreamState::Normal,
@@ -48,12 +637,60 @@ impl ProxyChannel {
             handshake: Handshake::new(),
             closing: Closing::NotYet,
             pending_pong: None,
+            pongs: 0,
+            pending_heartbeat: false,
+            pending_notice: false,
+            pending_ping: false,
             pending_close: None,
-            frame_decoder: FrameDecoder::default(),
-            frame_encoder: FrameEncoder::default(),
+            frame_decoder,
+            frame_encoder,
+            config: config.clone(),
+            terminated: false,
+            started_at: SystemTime::now(),
+            request_line: None,
+            request_path: None,
+            response_headers: Vec::new(),
+            response_status: None,
+            coalescing_timer: None,
+            first_byte_timer: config.time_to_first_byte_budget.map(Timer::new),
+            app_handshake: None,
+            sessions,
+            session: None,
+            close_webhook,
+            connection,
+            parked,
+            connector,
+            resume_token: None,
+            close_code: None,
+            deadline: None,
+            deadline_timer: None,
+            close_timer: None,
+            heartbeat_timer: None,
+            heartbeat_mark: 0,
+            keepalive_timer: None,
+            awaiting_pong: false,
+            idle_timer: None,
+            idle_mark: 0,
+            poll_window_start: Instant::now(),
+            poll_time: Duration::ZERO,
+            over_poll_time_budget: false,
+            lag_timer: None,
+            lag_warned: false,
+            read_budget: usize::MAX,
+            over_capacity: false,
+            half_closed: false,
+            real_write_shut: false,
+            log_level,
+            handshake_error: None,
         }
     }
 
+    /// Makes the channel answer the handshake request with `503 Service Unavailable` because
+    /// the server has too many connections.
+    pub fn reject_over_capacity(&mut self) {
+        self.over_capacity = true;
+    }
+
     fn process_handshake(&mut self, cx: &mut Context) -> bool {
         loop {
             match mem::replace(&mut self.handshake, Handshake::Done) {
@@ -66,7 +703,9 @@ impl ProxyChannel {
                     match result.and_then(|()| decoder.finish_decoding()) {
                         Err(e) => {
                             log::warn!("Malformed HTTP request: {}", e);
-                            self.handshake = Handshake::response_bad_request();
+                            self.handshake_error =
+                                Some(ErrorKind::HandshakeFailed.takes_over(e).into());
+                            self.handshake = Handshake::response_bad_request(&self.config);
                         }
                         Ok(request) => {
                             log::debug!("Received a WebSocket handshake request");
@@ -74,111 +713,331 @@ impl ProxyChannel {
                             log::debug!("Target: {}", request.request_target());
                             log::debug!("Version: {}", request.http_version());
                             log::debug!("Header: {}", request.header());
+                            self.request_line = Some(format!(
+                                "{} {} {}",
+                                request.method(),
+                                request.request_target(),
+                                request.http_version()
+                            ));
+                            self.request_path = Some(request.request_target().to_string());
+
+                            if self.over_capacity {
+                                log::warn!("Too many connections; rejects the handshake request");
+                                self.handshake_error = Some(
+                                    ErrorKind::HandshakeFailed
+                                        .cause("too many connections")
+                                        .into(),
+                                );
+                                self.handshake = Handshake::response_unavailable(&self.config);
+                                continue;
+                            }
 
-                            match track!(self.handle_handshake_request(&request)) {
-                                Err(e) => {
-                                    log::warn!("Invalid WebSocket handshake request: {}", e);
-                                    self.handshake = Handshake::response_bad_request();
+                            match self.handle_handshake_request(&request) {
+                                Err(rejection) => {
+                                    log::warn!(
+                                        "Invalid WebSocket handshake request: {}",
+                                        rejection
+                                    );
+                                    self.handshake_error =
+                                        Some(ErrorKind::HandshakeFailed.cause(rejection).into());
+                                    self.handshake = match rejection {
+                                        HandshakeRejection::UriTooLong => {
+                                            Handshake::response_uri_too_long(&self.config)
+                                        }
+                                        HandshakeRejection::UpgradeRequired => {
+                                            Handshake::response_upgrade_required(&self.config)
+                                        }
+                                        HandshakeRejection::ForbiddenOrigin => {
+                                            Handshake::response_forbidden(&self.config)
+                                        }
+                                        HandshakeRejection::NoRoute => {
+                                            Handshake::response_not_found(&self.config)
+                                        }
+                                        HandshakeRejection::Observer(status) => {
+                                            Handshake::response_rejected(status, &self.config)
+                                        }
+                                        _ => Handshake::response_bad_request(&self.config),
+                                    };
+                                }
+                                Ok(key) if self.config.validate_only => {
+                                    self.handshake = self.response_accepted(&key, &request);
+                                }
+                                Ok(key) if self.config.app_handshake_pattern.is_some() => {
+                                    // The real server is connected after the application-level
+                                    // handshake has been validated
+                                    self.handshake = self.response_accepted(&key, &request);
+                                    self.app_handshake = Some(AppHandshake::RecvFrame(Vec::new()));
+                                }
+                                Ok(key)
+                                    if self.config.handshake_order
+                                        == HandshakeOrder::RespondFirst =>
+                                {
+                                    self.handshake = self.response_accepted(&key, &request);
+                                    log::debug!("Tries to connect the real server");
+                                    let future = self.connect_real_server();
+                                    self.app_handshake = Some(AppHandshake::Connect(future));
                                 }
                                 Ok(key) => {
                                     log::debug!("Tries to connect the real server");
-                                    let future = TcpStream::connect(self.real_server_addr);
+                                    let future = self.connect_real_server();
                                     self.handshake =
-                                        Handshake::ConnectToRealServer(Box::pin(future), key);
+                                        Handshake::ConnectToRealServer(future, key, request);
                                 }
                             }
                         }
                     }
                 }
-                Handshake::ConnectToRealServer(mut f, key) => {
-                    match Pin::new(&mut f).poll(cx).map_err(Error::from) {
-                        Poll::Pending => {
-                            self.handshake = Handshake::ConnectToRealServer(f, key);
-                            break;
-                        }
-                        Poll::Ready(Err(e)) => {
-                            log::warn!("Cannot connect to the real server: {}", e);
-                            self.handshake = Handshake::response_unavailable();
-                        }
-                        Poll::Ready(Ok(stream)) => {
-                            log::debug!("Connected to the real server");
-                            let _ = stream.set_nodelay(true);
-                            self.handshake = Handshake::response_accepted(&key);
-                            self.real_stream = Some(stream);
-                        }
+                Handshake::ConnectToRealServer(mut f, key, request) => match Pin::new(&mut f)
+                    .poll(cx)
+                {
+                    Poll::Pending => {
+                        self.handshake = Handshake::ConnectToRealServer(f, key, request);
+                        break;
                     }
-                }
-                Handshake::SendResponse(mut encoder, succeeded) => {
+                    Poll::Ready(Err(e)) => {
+                        log::warn!("Cannot connect to the real server: {}", e);
+                        self.handshake_error = Some(ErrorKind::BackendUnavailable.cause(e).into());
+                        self.handshake = Handshake::response_unavailable(&self.config);
+                    }
+                    Poll::Ready(Ok(stream)) => {
+                        log::debug!("Connected to the real server");
+                        let _ = stream.set_nodelay(true);
+                        self.handshake = self.response_accepted(&key, &request);
+                        self.real_stream = Some(stream);
+                    }
+                },
+                Handshake::SendResponse(mut encoder, status) => {
                     if let Err(e) = track!(encoder.encode_to_write_buf(&mut self.ws_wbuf)) {
                         log::warn!("Cannot write a handshake response: {}", e);
                         return false;
                     }
                     if encoder.is_idle() {
+                        // The response only has to be in `ws_wbuf` (not flushed yet) here:
+                        // relaying starts within the same poll, so any data the real server
+                        // has already sent is queued right behind the response.
                         log::debug!("Handshake response has been written");
-                        if succeeded {
-                            log::info!("WebSocket handshake succeeded");
+                        self.response_status = Some(status);
+                        if status == 101 {
+                            log::log!(self.log_level, "WebSocket handshake succeeded");
+                            if let Some(observer) = &self.config.observer {
+                                observer.on_open(self.ws_stream.peer_addr().ok());
+                            }
                             self.handshake = Handshake::Done;
+                            if self.config.write_coalescing_window.is_some() {
+                                // The response should not wait for the coalescing window
+                                let writer = SyncWriter::new(&mut self.ws_stream, cx);
+                                if let Err(e) = track!(self.ws_wbuf.flush(writer)) {
+                                    log::warn!("Cannot write a handshake response: {}", e);
+                                    return false;
+                                }
+                            }
                         } else {
-                            return false;
+                            self.handshake = Handshake::Failed;
                         }
                     } else {
-                        self.handshake = Handshake::SendResponse(encoder, succeeded);
+                        self.handshake = Handshake::SendResponse(encoder, status);
                     }
                     break;
                 }
                 Handshake::Done => {
                     break;
                 }
+                Handshake::Failed => {
+                    self.handshake = Handshake::Failed;
+                    break;
+                }
             }
         }
         true
     }
 
-    fn handle_handshake_request(&mut self, request: &Request<()>) -> Result<WebSocketKey> {
-        track_assert_eq!(request.method().as_str(), "GET", ErrorKind::InvalidInput);
-        track_assert_eq!(
-            request.http_version(),
-            HttpVersion::V1_1,
-            ErrorKind::InvalidInput
-        );
+    fn response_accepted(&mut self, key: &WebSocketKey, request: &Request<()>) -> Handshake<R> {
+        if let Some(hook) = &self.config.response_header_hook {
+            // The headers of the hook precede the selected subprotocol
+            let mut headers = hook.call(request);
+            headers.append(&mut self.response_headers);
+            self.response_headers = headers;
+        }
+        Handshake::response_accepted(key, &self.config, &self.response_headers)
+    }
+
+    fn handle_handshake_request(
+        &mut self,
+        request: &Request<()>,
+    ) -> std::result::Result<WebSocketKey, HandshakeRejection> {
+        if self
+            .config
+            .max_uri_length
+            .is_some_and(|n| request.request_target().as_str().len() > n)
+        {
+            return Err(HandshakeRejection::UriTooLong);
+        }
+        if request.method().as_str() != "GET" {
+            return Err(HandshakeRejection::BadMethod);
+        }
+        if request.http_version() != HttpVersion::V1_1 {
+            return Err(HandshakeRejection::BadVersion);
+        }
 
         let mut key = None;
+        let mut session_id = None;
+        let mut offered_protocols = Vec::new();
+        let mut origin = None;
+        let mut upgrade = false;
         for field in request.header().fields() {
             let name = field.name();
             let value = field.value();
             if name.eq_ignore_ascii_case("upgrade") {
-                track_assert_eq!(value, "websocket", ErrorKind::InvalidInput);
+                upgrade = true;
+                if value != "websocket" {
+                    return Err(HandshakeRejection::BadUpgrade);
+                }
             } else if name.eq_ignore_ascii_case("connection") {
-                let mut values = value.split(',');
-                track_assert!(values.any(|v| v.trim() == "Upgrade"), ErrorKind::InvalidInput; value);
+                if !value.split(',').any(|v| v.trim() == "Upgrade") {
+                    return Err(HandshakeRejection::BadConnection);
+                }
             } else if name.eq_ignore_ascii_case("sec-websocket-key") {
                 key = Some(value.to_owned());
             } else if name.eq_ignore_ascii_case("sec-websocket-version") {
-                track_assert_eq!(value, "13", ErrorKind::InvalidInput);
+                if value != "13" {
+                    return Err(HandshakeRejection::BadWebSocketVersion);
+                }
+            } else if name.eq_ignore_ascii_case("origin") {
+                origin = Some(value.to_owned());
+            } else if name.eq_ignore_ascii_case("sec-websocket-protocol") {
+                offered_protocols.extend(value.split(',').map(|p| p.trim().to_owned()));
+            } else if self
+                .config
+                .session_header
+                .as_ref()
+                .is_some_and(|h| name.eq_ignore_ascii_case(h))
+            {
+                session_id = Some(value.to_owned());
+            } else if self
+                .config
+                .resume_header
+                .as_ref()
+                .is_some_and(|h| name.eq_ignore_ascii_case(h))
+            {
+                self.resume_token = Some(value.to_owned());
+            } else if self
+                .config
+                .deadline_header
+                .as_ref()
+                .is_some_and(|h| name.eq_ignore_ascii_case(h))
+            {
+                let secs = value.parse().map_err(|_| HandshakeRejection::BadDeadline)?;
+                let deadline = UNIX_EPOCH.checked_add(Duration::from_secs(secs));
+                self.deadline = Some(deadline.ok_or(HandshakeRejection::BadDeadline)?);
             }
         }
 
-        let key = track_assert_some!(key, ErrorKind::InvalidInput);
+        if !upgrade && key.is_none() {
+            return Err(HandshakeRejection::UpgradeRequired);
+        }
+        let key = key.ok_or(HandshakeRejection::MissingKey)?;
+        if !self.config.allowed_origins.is_empty() {
+            let allowed = origin.as_ref().is_some_and(|o| {
+                self.config
+                    .allowed_origins
+                    .iter()
+                    .any(|pattern| util::matches_wildcard(pattern, o))
+            });
+            if !allowed {
+                log::debug!("Origin: {:?}", origin);
+                return Err(HandshakeRejection::ForbiddenOrigin);
+            }
+        }
+        if !self.config.subprotocols.is_empty() && !offered_protocols.is_empty() {
+            let selected = offered_protocols
+                .iter()
+                .find(|p| self.config.subprotocols.contains(p));
+            match selected {
+                Some(protocol) => {
+                    log::debug!("Selected subprotocol: {}", protocol);
+                    self.response_headers
+                        .push(("Sec-WebSocket-Protocol".to_owned(), protocol.clone()));
+                }
+                None if self.config.require_subprotocol => {
+                    return Err(HandshakeRejection::UnsupportedSubprotocol);
+                }
+                None => {}
+            }
+        }
+        let mut real_server_addr = self.real_server_addr;
+        if !self.config.routes.is_empty() {
+            let target = request.request_target().as_str();
+            let addr =
+                route::select(&self.config.routes, target).ok_or(HandshakeRejection::NoRoute)?;
+            log::debug!("Routes {:?} to {}", target, addr);
+            real_server_addr = addr;
+        }
+        match self
+            .config
+            .observer
+            .as_ref()
+            .map(|o| o.on_handshake(request))
+        {
+            Some(Decision::Reject(status)) => return Err(HandshakeRejection::Observer(status)),
+            Some(Decision::Route(addr)) => {
+                log::debug!("Observer routes the request to {}", addr);
+                real_server_addr = addr;
+            }
+            Some(Decision::Allow) | None => {}
+        }
+        self.real_server_addr = real_server_addr;
+        if let Some(id) = session_id {
+            self.session = Some(self.sessions.register(id));
+        }
         Ok(WebSocketKey(key))
     }
 
     fn process_relay(&mut self, cx: &mut Context) -> Result<()> {
         if let Err(e) = track!(self.handle_real_stream(cx)) {
             log::warn!("{}", e);
-            track!(self.starts_closing(1001, false))?;
+            let timed_out = e
+                .concrete_cause::<io::Error>()
+                .is_some_and(|e| e.kind() == io::ErrorKind::TimedOut);
+            let code = if timed_out { 1011 } else { 1001 };
+            track!(self.starts_closing(Some(code), false))?;
         }
         if let Err(e) = track!(self.handle_ws_stream()) {
             log::warn!("{}", e);
-            track!(self.starts_closing(1002, false))?;
+            let violation = self.frame_decoder.violation();
+            self.pending_notice = violation == Some(Violation::MessageTooBig);
+            let code = violation.map_or(1002, Violation::close_code);
+            track!(self.starts_closing(Some(code), false))?;
         }
         Ok(())
     }
 
     fn handle_real_stream(&mut self, cx: &mut Context) -> Result<()> {
+        if let Some(AppHandshake::Connect(f)) = self.app_handshake.as_mut() {
+            match Pin::new(f).poll(cx) {
+                Poll::Pending => return Ok(()),
+                Poll::Ready(Err(e)) => {
+                    log::warn!("Cannot connect to the real server: {}", e);
+                    track!(self.starts_closing_with_reason(
+                        Some(1011),
+                        "real server unavailable",
+                        false
+                    ))?;
+                    return Ok(());
+                }
+                Poll::Ready(Ok(stream)) => {
+                    self.app_handshake = None;
+                    log::debug!("Connected to the real server");
+                    let _ = stream.set_nodelay(true);
+                    self.real_stream = Some(stream);
+                }
+            }
+        }
+
+        let opcode = self.outgoing_opcode();
         if let Some(stream) = self.real_stream.as_mut() {
             self.real_stream_rstate = track!(self
                 .frame_encoder
-                .start_encoding_data(SyncReader::new(stream, cx)))?;
+                .start_encoding_data(SyncReader::new(stream, cx), opcode))?;
             self.real_stream_wstate = track!(self
                 .frame_decoder
                 .write_decoded_data(SyncWriter::new(stream, cx)))?;
@@ -186,12 +1045,47 @@ impl ProxyChannel {
         Ok(())
     }
 
+    fn outgoing_opcode(&self) -> Opcode {
+        match self.config.frame_type {
+            FrameType::Binary => Opcode::BinaryFrame,
+            FrameType::Text => Opcode::TextFrame,
+            FrameType::Mirror => self
+                .frame_decoder
+                .last_data_opcode()
+                .unwrap_or(Opcode::BinaryFrame),
+        }
+    }
+
     fn handle_ws_stream(&mut self) -> Result<()> {
         if self.frame_encoder.is_idle() {
             if let Some(data) = self.pending_pong.take() {
                 log::debug!("Sends Ping frame: {:?}", data);
                 track!(self.frame_encoder.start_encoding(Frame::Pong { data }))?;
+                self.pongs += 1;
+            }
+        }
+        if self.frame_encoder.is_idle() && self.pending_heartbeat {
+            if let Some((payload, _)) = &self.config.client_heartbeat {
+                log::debug!("Sends heartbeat frame: {:?}", payload);
+                let opcode = self.outgoing_opcode();
+                track!(self.frame_encoder.start_encoding_message(opcode, payload))?;
+            }
+            self.pending_heartbeat = false;
+        }
+        if self.frame_encoder.is_idle() && self.pending_notice {
+            if let Some(payload) = &self.config.overflow_notice {
+                log::debug!("Sends overflow notice frame: {:?}", payload);
+                let opcode = self.outgoing_opcode();
+                track!(self.frame_encoder.start_encoding_message(opcode, payload))?;
             }
+            self.pending_notice = false;
+        }
+        if self.frame_encoder.is_idle() && self.pending_ping {
+            log::debug!("Sends keepalive Ping frame");
+            track!(self
+                .frame_encoder
+                .start_encoding(Frame::Ping { data: Vec::new() }))?;
+            self.pending_ping = false;
         }
         if self.frame_encoder.is_idle() {
             if let Some(frame) = self.pending_close.take() {
@@ -200,16 +1094,38 @@ impl ProxyChannel {
         }
 
         track!(self.frame_encoder.encode_to_write_buf(&mut self.ws_wbuf))?;
-        if self.frame_encoder.is_idle() && self.closing.is_client_closed() {
-            self.closing = Closing::Closed;
-        }
 
-        track!(self.frame_decoder.decode_from_read_buf(&mut self.ws_rbuf))?;
-        if self.frame_decoder.is_idle() {
+        // Several frames may have been read at once, so decoding goes on after each frame
+        loop {
+            if self.half_closed && self.ws_rbuf.is_empty() {
+                // Nothing more will be read from the client
+                break;
+            }
+            track!(self.frame_decoder.decode_from_read_buf(&mut self.ws_rbuf))?;
+            track!(self.collect_app_handshake())?;
+            track!(self.discard_validated_data())?;
+            if let Some(opcode) = self.frame_decoder.last_data_opcode() {
+                if !self.config.accepted_frame_types.accepts(opcode) && self.closing.is_not_yet() {
+                    log::warn!("Received a data frame of an unaccepted type: {:?}", opcode);
+                    track!(self.starts_closing_with_reason(
+                        Some(1003),
+                        "unsupported frame type",
+                        false
+                    ))?;
+                }
+            }
+            if !self.frame_decoder.is_idle() {
+                break;
+            }
             let frame = track!(self.frame_decoder.finish_decoding())?;
             log::debug!("Received frame: {:?}", frame);
             track!(self.handle_frame(frame))?;
         }
+
+        let close_sent = self.frame_encoder.is_idle() && self.pending_close.is_none();
+        if close_sent && self.closing.is_client_closed() {
+            self.closing = Closing::Closed;
+        }
         Ok(())
     }
 
@@ -217,13 +1133,15 @@ impl ProxyChannel {
         match frame {
             Frame::ConnectionClose { code, reason } => {
                 log::info!(
-                    "Received Close frame: code={}, reason={:?}",
+                    "Received Close frame: code={:?}, reason={:?}",
                     code,
-                    String::from_utf8(reason)
+                    String::from_utf8_lossy(&reason)
                 );
                 match self.closing {
                     Closing::NotYet => {
-                        track!(self.starts_closing(code, true))?;
+                        // Echoes the status code and reason (RFC 6455, 5.5.1)
+                        let reason = std::str::from_utf8(&reason).unwrap_or("");
+                        track!(self.starts_closing_with_reason(code, reason, true))?;
                     }
                     Closing::InProgress {
                         ref mut client_closed,
@@ -233,104 +1151,623 @@ impl ProxyChannel {
                     _ => track_panic!(ErrorKind::Other; self.closing),
                 }
             }
+            Frame::Ping { .. } if !self.closing.is_not_yet() => {}
             Frame::Ping { data } => {
-                if self.closing.is_not_yet() {
+                if self.config.max_pongs.is_some_and(|n| self.pongs >= n) {
+                    log::debug!(
+                        "Ignores Ping frame: {} Pong frames have been sent",
+                        self.pongs
+                    );
+                    if self.config.close_over_max_pongs {
+                        track!(self.starts_closing_with_reason(
+                            Some(1008),
+                            "too many pings",
+                            false
+                        ))?;
+                    }
+                } else {
                     self.pending_pong = Some(data);
                 }
             }
-            Frame::Pong { .. } | Frame::Data => {}
+            Frame::Data => {
+                if let Some(AppHandshake::RecvFrame(payload)) = self.app_handshake.take() {
+                    track!(self.check_app_handshake(&payload))?;
+                }
+            }
+            Frame::Pong { .. } => {
+                self.awaiting_pong = false;
+            }
+        }
+        Ok(())
+    }
+
+    fn collect_app_handshake(&mut self) -> Result<()> {
+        while let Some(AppHandshake::RecvFrame(ref mut payload)) = self.app_handshake {
+            if self.frame_decoder.is_data_empty() {
+                break;
+            }
+            track!(self.frame_decoder.write_decoded_data(&mut *payload))?;
+            if payload.len() > self.config.buffer_size() {
+                log::warn!("Application-level handshake is too large");
+                return track!(self.rejects_app_handshake());
+            }
+            track!(self.frame_decoder.decode_from_read_buf(&mut self.ws_rbuf))?;
+        }
+        Ok(())
+    }
+
+    fn discard_validated_data(&mut self) -> Result<()> {
+        // Frames are only validated, so their payloads are discarded
+        while self.config.validate_only && !self.frame_decoder.is_data_empty() {
+            track!(self.frame_decoder.write_decoded_data(io::sink()))?;
+            track!(self.frame_decoder.decode_from_read_buf(&mut self.ws_rbuf))?;
         }
         Ok(())
     }
 
-    fn starts_closing(&mut self, code: u16, client_closed: bool) -> Result<()> {
+    fn connect_real_server(&self) -> ConnectFuture<R> {
+        if let Some(stream) = self.resume_token.as_ref().and_then(|t| self.parked.take(t)) {
+            log::info!("Resumes the parked real server connection");
+            return Box::pin(async move { Ok(stream) });
+        }
+        let path = self.request_path.as_deref().unwrap_or("/");
+        let client = self
+            .ws_stream
+            .peer_addr()
+            .ok()
+            .zip(self.ws_stream.local_addr().ok());
+        backend::connect(
+            &self.connector,
+            self.real_server_addr,
+            &self.config,
+            path,
+            client,
+        )
+    }
+
+    fn check_app_handshake(&mut self, payload: &[u8]) -> Result<()> {
+        let pattern =
+            track_assert_some!(self.config.app_handshake_pattern.as_ref(), ErrorKind::Other);
+        if pattern.is_match(payload) {
+            log::debug!("Application-level handshake succeeded");
+            log::debug!("Tries to connect the real server");
+            let future = self.connect_real_server();
+            self.app_handshake = Some(AppHandshake::Connect(future));
+            Ok(())
+        } else {
+            log::warn!("Application-level handshake does not match: {:?}", payload);
+            track!(self.rejects_app_handshake())
+        }
+    }
+
+    fn rejects_app_handshake(&mut self) -> Result<()> {
+        track!(self.starts_closing_with_reason(Some(1008), "application handshake rejected", false))
+    }
+
+    fn starts_closing(&mut self, code: Option<u16>, client_closed: bool) -> Result<()> {
+        track!(self.starts_closing_with_reason(code, "", client_closed))
+    }
+
+    fn starts_closing_with_reason(
+        &mut self,
+        code: Option<u16>,
+        reason: &str,
+        client_closed: bool,
+    ) -> Result<()> {
         track_assert_eq!(self.closing, Closing::NotYet, ErrorKind::Other);
+        self.app_handshake = None;
         self.real_stream = None;
         self.real_stream_rstate = StreamState::Eos;
         self.real_stream_wstate = StreamState::Eos;
-        self.closing = Closing::InProgress { client_closed };
+        // A half-closed client cannot send a Close frame, so only ours has to be sent
+        self.closing = Closing::InProgress {
+            client_closed: client_closed || self.half_closed,
+        };
+        self.close_code = code;
         self.pending_close = Some(Frame::ConnectionClose {
             code,
-            reason: Vec::new(),
+            reason: reason.as_bytes().to_owned(),
         });
         Ok(())
     }
 
-    fn is_ws_stream_eos(&self) -> bool {
-        self.ws_rbuf.stream_state().is_eos() || self.ws_wbuf.stream_state().is_eos()
+    fn is_ws_read_eos(&self) -> bool {
+        self.ws_rbuf.stream_state().is_eos()
+    }
+
+    fn is_ws_write_eos(&self) -> bool {
+        self.ws_wbuf.stream_state().is_eos()
     }
 
     fn is_real_stream_eos(&self) -> bool {
         self.real_stream_rstate.is_eos() || self.real_stream_wstate.is_eos()
     }
 
+    fn shutdown_real_write_if_drained(&mut self) {
+        if !self.half_closed || self.real_write_shut || !self.frame_decoder.is_data_empty() {
+            return;
+        }
+        if let Some(stream) = self
+            .real_stream
+            .as_ref()
+            .filter(|_| self.ws_rbuf.is_empty())
+        {
+            // Propagates the half-close so that the real server sees the end of the client data
+            log::debug!("Shuts down the write side of the real server connection");
+            if let Err(e) = stream.shutdown_write() {
+                log::warn!(
+                    "Cannot shut down the write side of the real server connection: {}",
+                    e
+                );
+            }
+            self.real_write_shut = true;
+        }
+    }
+
     fn would_ws_stream_block(&self) -> bool {
         let empty_write =
             self.ws_wbuf.is_empty() && self.pending_close.is_none() && self.pending_pong.is_none();
-        self.ws_rbuf.stream_state().would_block()
-            && (empty_write || self.ws_wbuf.stream_state().would_block())
+        // A full read buffer waits for the decoder, which in turn waits for the real server
+        let read_done = self.ws_rbuf.stream_state().would_block()
+            || self.ws_rbuf.is_full()
+            || (self.half_closed && self.ws_rbuf.is_empty());
+        read_done
+            && (empty_write
+                || self.ws_wbuf.stream_state().would_block()
+                || self.coalescing_timer.is_some())
+    }
+
+    fn should_flush_ws_stream(&mut self, cx: &mut Context) -> bool {
+        let window = match self.config.write_coalescing_window {
+            Some(window) if self.handshake.done() && self.closing.is_not_yet() => window,
+            _ => return true,
+        };
+        if self.ws_wbuf.is_empty() || self.ws_wbuf.is_full() {
+            self.coalescing_timer = None;
+            return true;
+        }
+
+        let timer = self
+            .coalescing_timer
+            .get_or_insert_with(|| Timer::new(window));
+        if timer.poll_expired(cx) {
+            self.coalescing_timer = None;
+            true
+        } else {
+            false
+        }
     }
 
     fn would_real_stream_block(&self) -> bool {
-        self.real_stream_rstate.would_block()
-            && (self.frame_decoder.is_data_empty() || self.real_stream_wstate.would_block())
+        if self.config.validate_only {
+            // There is no real stream, but buffered frames can still be processed
+            return self.ws_rbuf.is_empty();
+        }
+        // Once closing has started the real stream is dropped and has nothing left to do
+        self.real_stream.is_none()
+            || self.real_stream_rstate.would_block()
+                && (self.frame_decoder.is_data_empty() || self.real_stream_wstate.would_block())
     }
-}
-impl Future for ProxyChannel {
-    type Output = Result<()>;
 
-    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
-        let this = self.get_mut();
+    fn is_backend_lagging(&self) -> bool {
+        self.real_stream.is_some()
+            && !self.frame_decoder.is_data_empty()
+            && self.real_stream_wstate.would_block()
+    }
+
+    fn check_poll_time(&mut self, elapsed: Duration) -> Result<bool> {
+        let Some((budget, window)) = self.config.poll_time_budget else {
+            return Ok(false);
+        };
+        if self.poll_window_start.elapsed() >= window {
+            self.poll_window_start = Instant::now();
+            self.poll_time = Duration::ZERO;
+            self.over_poll_time_budget = false;
+        }
+        self.poll_time += elapsed;
+        if self.poll_time <= budget {
+            return Ok(false);
+        }
+
+        if !self.over_poll_time_budget {
+            log::warn!(
+                "Channel has been polled for {:?} within {:?}: conn_id={}",
+                self.poll_time,
+                window,
+                self.connection.key()
+            );
+            self.over_poll_time_budget = true;
+        }
+        if self.config.close_over_poll_time_budget
+            && self.handshake.done()
+            && self.closing.is_not_yet()
+        {
+            track!(self.starts_closing_with_reason(
+                Some(1008),
+                "poll time budget exceeded",
+                false
+            ))?;
+            return Ok(true);
+        }
+        Ok(false)
+    }
+
+    fn take_handshake_error(&mut self) -> Error {
+        let e = self.handshake_error.take().unwrap_or_else(|| {
+            ErrorKind::HandshakeFailed
+                .cause("WebSocket handshake cannot be completed")
+                .into()
+        });
+        track!(e)
+    }
+
+    fn relayed_bytes(&self) -> u64 {
+        self.frame_encoder.data_bytes() + self.frame_decoder.data_bytes()
+    }
+
+    fn access_log_line(&self) -> String {
+        format!(
+            "{} - - {} \"{}\" {} {}",
+            self.ws_stream
+                .peer_addr()
+                .map_or_else(|_| "-".to_owned(), |a| a.ip().to_string()),
+            util::format_clf_date(self.started_at),
+            self.request_line.as_deref().unwrap_or("-"),
+            self.response_status
+                .map_or_else(|| "-".to_owned(), |s| s.to_string()),
+            self.relayed_bytes()
+        )
+    }
+
+    fn is_close_incomplete(&self) -> bool {
+        !self.terminated && self.handshake.done() && self.closing != Closing::Closed
+    }
+
+    fn poll_channel(&mut self, cx: &mut Context) -> Poll<Result<()>> {
         loop {
             // WebSocket TCP stream I/O
-            track!(this.ws_rbuf.fill(SyncReader::new(&mut this.ws_stream, cx)))?;
-            track!(this.ws_wbuf.flush(SyncWriter::new(&mut this.ws_stream, cx)))?;
-            if this.is_ws_stream_eos() {
-                log::info!("TCP stream for WebSocket has been closed");
-                return Poll::Ready(Ok(()));
+            let reader = SyncReader::new(&mut self.ws_stream, cx);
+            let unread = self.ws_rbuf.len();
+            track!(self
+                .ws_rbuf
+                .fill(CappedReader::new(reader, &mut self.read_budget)))?;
+            if self.ws_rbuf.len() > unread {
+                self.idle_timer = None;
             }
-
-            // WebSocket handshake
-            if !this.process_handshake(cx) {
-                log::warn!("WebSocket handshake cannot be completed");
+            if self.should_flush_ws_stream(cx) {
+                track!(self.ws_wbuf.flush(SyncWriter::new(&mut self.ws_stream, cx)))?;
+            }
+            if self.is_ws_write_eos() {
+                log::log!(self.log_level, "TCP stream for WebSocket has been closed");
                 return Poll::Ready(Ok(()));
             }
-            if !this.handshake.done() {
-                if this.would_ws_stream_block() {
-                    return Poll::Pending;
+            if self.is_ws_read_eos() && !self.half_closed {
+                // A client that may resume is assumed to have gone, so that its real server
+                // connection is parked
+                if !self.handshake.done()
+                    || !self.closing.is_not_yet()
+                    || self.resume_token.is_some()
+                {
+                    log::log!(self.log_level, "TCP stream for WebSocket has been closed");
+                    return Poll::Ready(Ok(()));
                 }
-                continue;
+                // The client may still receive data after closing its write side (TCP half-close)
+                log::log!(
+                    self.log_level,
+                    "TCP stream for WebSocket has been half-closed by the client"
+                );
+                self.half_closed = true;
             }
 
-            if this.closing == Closing::Closed {
-                log::info!("WebSocket channel has been closed normally");
-                return Poll::Ready(Ok(()));
-            }
+            // Time-to-first-byte budget
+            if self.first_byte_timer.is_some() {
+                if self.relayed_bytes() > 0 {
+                    self.first_byte_timer = None;
+                } else if self
+                    .first_byte_timer
+                    .as_mut()
+                    .is_some_and(|t| t.poll_expired(cx))
+                {
+                    log::warn!("Time-to-first-byte budget has been exceeded");
+                    self.first_byte_timer = None;
+                    match self.handshake {
+                        Handshake::RecvRequest(_) => return Poll::Ready(Ok(())),
+                        Handshake::ConnectToRealServer(..) => {
+                            self.handshake = Handshake::response_unavailable(&self.config);
+                        }
+                        Handshake::Done if self.closing.is_not_yet() => {
+                            track!(self.starts_closing_with_reason(
+                                Some(1008),
+                                "time-to-first-byte budget exceeded",
+                                false
+                            ))?;
+                        }
+                        _ => {}
+                    }
+                }
+            }
+
+            // Close request from the server
+            if self.connection.poll_signaled(cx) {
+                if !self.handshake.done() {
+                    log::info!("Connection has been closed by the server");
+                    return Poll::Ready(Ok(()));
+                }
+                if self.closing.is_not_yet() {
+                    log::info!("Connection is being closed by the server");
+                    track!(self.starts_closing_with_reason(
+                        Some(1001),
+                        "connection closed by server",
+                        false
+                    ))?;
+                }
+            }
+
+            // Duplicate session
+            if self.session.as_mut().is_some_and(|s| s.poll_signaled(cx)) {
+                log::info!("Session has been taken over by a new connection");
+                self.session = None;
+                if !self.handshake.done() {
+                    return Poll::Ready(Ok(()));
+                }
+                if self.closing.is_not_yet() {
+                    let code = self.config.session_close_code.unwrap_or(4000);
+                    track!(self.starts_closing_with_reason(Some(code), "session replaced", false))?;
+                }
+            }
+
+            // Per-connection deadline
+            if self.handshake.done() && self.closing.is_not_yet() {
+                if let Some(deadline) = self.deadline.take() {
+                    let timeout = deadline
+                        .duration_since(SystemTime::now())
+                        .unwrap_or_default();
+                    self.deadline_timer = Some(Timer::new(timeout));
+                }
+                if self
+                    .deadline_timer
+                    .as_mut()
+                    .is_some_and(|t| t.poll_expired(cx))
+                {
+                    log::info!("Connection deadline has been reached");
+                    self.deadline_timer = None;
+                    track!(self.starts_closing_with_reason(
+                        Some(1001),
+                        "connection deadline reached",
+                        false
+                    ))?;
+                }
+            }
+
+            // WebSocket handshake
+            if !self.process_handshake(cx) {
+                return Poll::Ready(Err(self.take_handshake_error()));
+            }
+            if self.handshake.failed() {
+                // Waits until the error response is flushed
+                if self.ws_wbuf.is_empty() {
+                    return Poll::Ready(Err(self.take_handshake_error()));
+                }
+                if self.ws_wbuf.stream_state().would_block() {
+                    return Poll::Pending;
+                }
+                continue;
+            }
+            if !self.handshake.done() {
+                if self.would_ws_stream_block() {
+                    return Poll::Pending;
+                }
+                continue;
+            }
+
+            if self.closing == Closing::Closed {
+                log::log!(self.log_level, "WebSocket channel has been closed normally");
+                return Poll::Ready(Ok(()));
+            }
 
             // Relay
-            track!(this.process_relay(cx))?;
-            if this.is_real_stream_eos() && this.closing.is_not_yet() {
-                log::info!("TCP stream for a real server has been closed");
-                track!(this.starts_closing(1000, false))?;
+            track!(self.process_relay(cx))?;
+            if self.is_real_stream_eos() && self.closing.is_not_yet() {
+                log::log!(
+                    self.log_level,
+                    "TCP stream for a real server has been closed"
+                );
+                track!(self.starts_closing(Some(1000), false))?;
+            }
+            self.shutdown_real_write_if_drained();
+            if self.closing == Closing::Closed {
+                continue;
+            }
+
+            // Closing handshake timeout (checked after relaying, which may start closing)
+            if let (Some(timeout), Closing::InProgress { .. }) =
+                (self.config.close_timeout, &self.closing)
+            {
+                let timer = self.close_timer.get_or_insert_with(|| Timer::new(timeout));
+                if timer.poll_expired(cx) {
+                    log::warn!("Closing handshake has timed out; drops the connection");
+                    return Poll::Ready(Ok(()));
+                }
+            }
+
+            // Client heartbeat (the timer restarts whenever data is relayed to the client)
+            if let (Some((_, interval)), true) =
+                (&self.config.client_heartbeat, self.closing.is_not_yet())
+            {
+                if self.heartbeat_mark != self.frame_encoder.data_bytes() {
+                    self.heartbeat_mark = self.frame_encoder.data_bytes();
+                    self.heartbeat_timer = None;
+                }
+                let interval = *interval;
+                let timer = self
+                    .heartbeat_timer
+                    .get_or_insert_with(|| Timer::new(interval));
+                if timer.poll_expired(cx) {
+                    self.heartbeat_timer = None;
+                    self.pending_heartbeat = true;
+                    continue;
+                }
+            }
+
+            // Keepalive (a Ping is sent per interval, and the previous one must have been answered)
+            if let (Some(interval), true) = (
+                self.config.keepalive_interval,
+                self.handshake.done() && self.closing.is_not_yet(),
+            ) {
+                let timer = self
+                    .keepalive_timer
+                    .get_or_insert_with(|| Timer::new(interval));
+                if timer.poll_expired(cx) {
+                    self.keepalive_timer = None;
+                    if self.awaiting_pong {
+                        log::warn!(
+                            "No Pong frame has been received from the client: conn_id={}",
+                            self.connection.key()
+                        );
+                        track!(self.starts_closing_with_reason(
+                            Some(1001),
+                            "keepalive timeout",
+                            false
+                        ))?;
+                    } else {
+                        self.pending_ping = true;
+                        self.awaiting_pong = true;
+                    }
+                    continue;
+                }
+            }
+
+            // Idle timeout (the timer restarts whenever data is read from either stream)
+            if let (Some(timeout), true) = (
+                self.config.idle_timeout,
+                self.handshake.done() && self.closing.is_not_yet(),
+            ) {
+                if self.idle_mark != self.frame_encoder.data_bytes() {
+                    self.idle_mark = self.frame_encoder.data_bytes();
+                    self.idle_timer = None;
+                }
+                let timer = self.idle_timer.get_or_insert_with(|| Timer::new(timeout));
+                if timer.poll_expired(cx) {
+                    log::info!(
+                        "Channel has been idle for {:?}: conn_id={}",
+                        timeout,
+                        self.connection.key()
+                    );
+                    self.idle_timer = None;
+                    track!(self.starts_closing_with_reason(Some(1001), "idle timeout", false))?;
+                    continue;
+                }
+            }
+
+            // Backend lag (decoded client data is waiting for the real server to accept it)
+            match self.config.backend_lag_warning {
+                Some(threshold) if self.is_backend_lagging() => {
+                    if !self.lag_warned {
+                        let timer = self.lag_timer.get_or_insert_with(|| Timer::new(threshold));
+                        if timer.poll_expired(cx) {
+                            log::warn!(
+                                "Real server has not accepted client data for {:?}: conn_id={}",
+                                threshold,
+                                self.connection.key()
+                            );
+                            self.lag_timer = None;
+                            self.lag_warned = true;
+                        }
+                    }
+                }
+                _ => {
+                    self.lag_timer = None;
+                    self.lag_warned = false;
+                }
             }
-            if this.would_ws_stream_block() && this.would_real_stream_block() {
+
+            if self.would_ws_stream_block() && self.would_real_stream_block() {
                 return Poll::Pending;
             }
         }
     }
 }
+impl<S: ClientStream, R: BackendStream> Future for ProxyChannel<S, R> {
+    type Output = Result<()>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        let this = self.get_mut();
+        this.read_budget = this.config.max_read_per_poll.unwrap_or(usize::MAX);
+        let started_at = Instant::now();
+        let result = this.poll_channel(cx);
+        if result.is_ready() {
+            this.terminated = true;
+            return result;
+        }
+        match this.check_poll_time(started_at.elapsed()) {
+            Err(e) => return Poll::Ready(Err(e)),
+            Ok(true) => {
+                // Wakes up to send the Close frame
+                cx.waker().wake_by_ref();
+            }
+            Ok(false) if this.read_budget == 0 => {
+                // Yields so that the rest of the data is read in the next poll
+                cx.waker().wake_by_ref();
+            }
+            Ok(false) => {}
+        }
+        result
+    }
+}
+impl<S: ClientStream, R: BackendStream> Drop for ProxyChannel<S, R> {
+    fn drop(&mut self) {
+        if self.config.log_format == LogFormat::Clf {
+            log::info!(target: "wstcp::access", "{}", self.access_log_line());
+        }
+        if self.is_close_incomplete() {
+            log::warn!(
+                "A proxy channel was dropped before the closing handshake completed: conn_id={}, peer={:?}",
+                self.connection.key(),
+                self.ws_stream.peer_addr().ok()
+            );
+        }
+        if self.handshake.done() && self.closing.is_not_yet() && !self.is_real_stream_eos() {
+            if let (Some(token), Some(stream)) = (self.resume_token.take(), self.real_stream.take())
+            {
+                log::info!("Parks the real server connection for resumption");
+                let window = self.config.resume_window.unwrap_or(DEFAULT_RESUME_WINDOW);
+                self.parked.park(token, stream, window);
+            }
+        }
+        if let (Some(observer), Some(101)) = (&self.config.observer, self.response_status) {
+            let code = match self.close_code {
+                Some(code) => code,
+                None if self.closing.is_not_yet() => 1006,
+                None => 1005,
+            };
+            observer.on_close(code);
+        }
+        if let Some(webhook) = &self.close_webhook {
+            if self.response_status == Some(101) {
+                webhook.notify(CloseEvent {
+                    client_addr: self.ws_stream.peer_addr().ok(),
+                    backend_addr: self.real_server_addr,
+                    close_code: self.close_code,
+                    bytes: self.relayed_bytes(),
+                    duration: self.started_at.elapsed().unwrap_or_default(),
+                });
+            }
+        }
+    }
+}
+
+type ResponseWithBodyEncoder =
+    TupleEncoder<(ResponseEncoder<NoBodyEncoder>, BytesEncoder<Vec<u8>>)>;
 
 #[allow(clippy::large_enum_variant)]
-enum Handshake {
+enum Handshake<R> {
     RecvRequest(RequestDecoder<NoBodyDecoder>),
-    ConnectToRealServer(
-        Pin<Box<(dyn Future<Output = async_std::io::Result<TcpStream>> + Send + 'static)>>,
-        WebSocketKey,
-    ),
-    SendResponse(ResponseEncoder<NoBodyEncoder>, bool),
+    ConnectToRealServer(ConnectFuture<R>, WebSocketKey, Request<()>),
+    SendResponse(ResponseWithBodyEncoder, u16),
     Done,
+    Failed,
 }
-impl Handshake {
+impl<R> Handshake<R> {
     fn new() -> Self {
         Handshake::RecvRequest(RequestDecoder::default())
     }
@@ -339,7 +1776,15 @@ impl Handshake {
         matches!(self, Handshake::Done)
     }
 
-    fn response_accepted(key: &WebSocketKey) -> Self {
+    fn failed(&self) -> bool {
+        matches!(self, Handshake::Failed)
+    }
+
+    fn response_accepted(
+        key: &WebSocketKey,
+        config: &ChannelConfig,
+        extra_headers: &[(String, String)],
+    ) -> Self {
         let hash = util::calc_accept_hash(key);
 
         unsafe {
@@ -354,51 +1799,153 @@ impl Handshake {
                 .add_field(HeaderField::new_unchecked("Upgrade", "websocket"))
                 .add_field(HeaderField::new_unchecked("Connection", "Upgrade"))
                 .add_field(HeaderField::new_unchecked("Sec-WebSocket-Accept", &hash));
+            Self::add_optional_fields(&mut response, config);
+            for (name, value) in &config.extra_response_headers {
+                response
+                    .header_mut()
+                    .add_field(HeaderField::new_unchecked(name, value));
+            }
+            for (name, value) in extra_headers {
+                match HeaderField::new(name, value) {
+                    Ok(field) => {
+                        response.header_mut().add_field(field);
+                    }
+                    Err(e) => log::warn!("Invalid response header {:?}: {}", name, e),
+                }
+            }
 
-            let encoder = ResponseEncoder::with_item(response).expect("Never fails");
-            Handshake::SendResponse(encoder, true)
+            let encoder = ResponseEncoder::with_item(response)
+                .expect("Never fails")
+                .chain(BytesEncoder::new());
+            Handshake::SendResponse(encoder, 101)
         }
     }
 
-    fn response_bad_request() -> Self {
+    fn response_bad_request(config: &ChannelConfig) -> Self {
+        Self::response_error(400, "Bad Request", Vec::new(), config)
+    }
+
+    fn response_forbidden(config: &ChannelConfig) -> Self {
+        Self::response_error(403, "Forbidden", Vec::new(), config)
+    }
+
+    fn response_not_found(config: &ChannelConfig) -> Self {
+        Self::response_error(404, "Not Found", Vec::new(), config)
+    }
+
+    fn response_upgrade_required(config: &ChannelConfig) -> Self {
+        let body = b"This is a WebSocket endpoint; connect with a WebSocket client.\n".to_vec();
+        let fields = [("Upgrade", "websocket"), ("Connection", "Upgrade")];
+        Self::response_error_with_fields(426, "Upgrade Required", &fields, body, config)
+    }
+
+    fn response_rejected(status: u16, config: &ChannelConfig) -> Self {
+        let status = if (400..600).contains(&status) {
+            status
+        } else {
+            500
+        };
+        let reason = match status {
+            400 => "Bad Request",
+            401 => "Unauthorized",
+            403 => "Forbidden",
+            404 => "Not Found",
+            429 => "Too Many Requests",
+            500 => "Internal Server Error",
+            503 => "Service Unavailable",
+            _ => "Rejected",
+        };
+        Self::response_error(status, reason, Vec::new(), config)
+    }
+
+    fn response_uri_too_long(config: &ChannelConfig) -> Self {
+        Self::response_error(414, "URI Too Long", Vec::new(), config)
+    }
+
+    fn response_unavailable(config: &ChannelConfig) -> Self {
+        let body = config.unavailable_body.clone().unwrap_or_default();
+        Self::response_error(503, "Service Unavailable", body.into_bytes(), config)
+    }
+
+    fn response_error(
+        status: u16,
+        reason: &'static str,
+        body: Vec<u8>,
+        config: &ChannelConfig,
+    ) -> Self {
+        Self::response_error_with_fields(status, reason, &[], body, config)
+    }
+
+    fn response_error_with_fields(
+        status: u16,
+        reason: &'static str,
+        fields: &[(&'static str, &'static str)],
+        body: Vec<u8>,
+        config: &ChannelConfig,
+    ) -> Self {
         unsafe {
             let mut response = Response::new(
                 HttpVersion::V1_1,
-                StatusCode::new_unchecked(400),
-                ReasonPhrase::new_unchecked("Bad Request"),
+                StatusCode::new_unchecked(status),
+                ReasonPhrase::new_unchecked(reason),
                 (),
             );
-            response
-                .header_mut()
-                .add_field(HeaderField::new_unchecked("Content-Length", "0"));
-            let encoder = ResponseEncoder::with_item(response).expect("Never fails");
-            Handshake::SendResponse(encoder, false)
+            for (name, value) in fields {
+                response
+                    .header_mut()
+                    .add_field(HeaderField::new_unchecked(name, value));
+            }
+            let content_length = body.len().to_string();
+            response.header_mut().add_field(HeaderField::new_unchecked(
+                "Content-Length",
+                &content_length,
+            ));
+            if !body.is_empty() {
+                response.header_mut().add_field(HeaderField::new_unchecked(
+                    "Content-Type",
+                    "text/plain; charset=utf-8",
+                ));
+            }
+            Self::add_optional_fields(&mut response, config);
+            let encoder = ResponseEncoder::with_item(response)
+                .expect("Never fails")
+                .chain(BytesEncoder::with_item(body).expect("Never fails"));
+            Handshake::SendResponse(encoder, status)
         }
     }
 
-    fn response_unavailable() -> Self {
-        unsafe {
-            let mut response = Response::new(
-                HttpVersion::V1_1,
-                StatusCode::new_unchecked(503),
-                ReasonPhrase::new_unchecked("Service Unavailable"),
-                (),
-            );
-            response
-                .header_mut()
-                .add_field(HeaderField::new_unchecked("Content-Length", "0"));
-            let encoder = ResponseEncoder::with_item(response).expect("Never fails");
-            Handshake::SendResponse(encoder, false)
+    fn add_optional_fields(response: &mut Response<()>, config: &ChannelConfig) {
+        let mut header = response.header_mut();
+        if let Some(server) = &config.server_header {
+            if util::is_valid_header_value(server) {
+                header.add_field(unsafe { HeaderField::new_unchecked("Server", server) });
+            } else {
+                log::warn!("Invalid `Server` header value: {:?}", server);
+            }
+        }
+        if config.emit_date {
+            let date = util::format_http_date(SystemTime::now());
+            header.add_field(unsafe { HeaderField::new_unchecked("Date", &date) });
         }
     }
 }
 
-impl std::fmt::Debug for Handshake {
+impl<R> std::fmt::Debug for Handshake<R> {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
         write!(f, "Handshake {{ .. }}")
     }
 }
 
+enum AppHandshake<R> {
+    RecvFrame(Vec<u8>),
+    Connect(ConnectFuture<R>),
+}
+impl<R> std::fmt::Debug for AppHandshake<R> {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "AppHandshake {{ .. }}")
+    }
+}
+
 #[derive(Debug, PartialEq, Eq)]
 enum Closing {
     NotYet,
@@ -445,6 +1992,34 @@ where
     }
 }
 
+/// Reader that reads at most `budget` bytes and then reports `WouldBlock`.
+#[derive(Debug)]
+struct CappedReader<'a, R> {
+    inner: R,
+    budget: &'a mut usize,
+}
+
+impl<'a, R: std::io::Read> CappedReader<'a, R> {
+    fn new(inner: R, budget: &'a mut usize) -> Self {
+        Self { inner, budget }
+    }
+}
+
+impl<'a, R: std::io::Read> std::io::Read for CappedReader<'a, R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        if *self.budget == 0 {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::WouldBlock,
+                "Read budget exhausted",
+            ));
+        }
+        let size = buf.len().min(*self.budget);
+        let size = self.inner.read(&mut buf[..size])?;
+        *self.budget -= size;
+        Ok(size)
+    }
+}
+
 #[derive(Debug)]
 struct SyncWriter<'a, 'b, 'c, T> {
     inner: &'a mut T,
@@ -481,3 +2056,2109 @@ where
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::testing::{capture_logs, captured_logs, read_response, HANDSHAKE_REQUEST};
+    use async_std::io::{ReadExt, WriteExt};
+    use async_std::net::TcpListener;
+    use bytecodec::DecodeExt;
+    use std::collections::VecDeque;
+    use std::net::Shutdown;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Mutex;
+    use std::task::Waker;
+
+    async fn spawn_channel(real_server_addr: SocketAddr, config: ChannelConfig) -> TcpStream {
+        let connection = Registry::default().register(0);
+        spawn_channel_with(
+            real_server_addr,
+            config,
+            Registry::default(),
+            None,
+            connection,
+            ParkedBackends::default(),
+        )
+        .await
+    }
+
+    async fn spawn_channel_with(
+        real_server_addr: SocketAddr,
+        config: ChannelConfig,
+        sessions: Registry<String>,
+        close_webhook: Option<CloseWebhook>,
+        connection: Registration<u64>,
+        parked: ParkedBackends,
+    ) -> TcpStream {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (stream, _) = listener.accept().await.unwrap();
+        async_std::task::spawn(ProxyChannel::new(
+            stream,
+            real_server_addr,
+            config,
+            sessions,
+            close_webhook,
+            connection,
+            parked,
+        ));
+        client
+    }
+
+    #[derive(Debug, Default)]
+    struct Pipe {
+        buf: VecDeque<u8>,
+        closed: bool,
+        waker: Option<Waker>,
+    }
+
+    /// One end of an in-memory duplex stream.
+    #[derive(Debug)]
+    struct MemoryStream {
+        rx: Arc<Mutex<Pipe>>,
+        tx: Arc<Mutex<Pipe>>,
+    }
+    impl MemoryStream {
+        fn pair() -> (Self, Self) {
+            let a = Arc::new(Mutex::new(Pipe::default()));
+            let b = Arc::new(Mutex::new(Pipe::default()));
+            let this = MemoryStream {
+                rx: a.clone(),
+                tx: b.clone(),
+            };
+            let peer = MemoryStream { rx: b, tx: a };
+            (this, peer)
+        }
+
+        fn close_tx(&self) {
+            let mut tx = self.tx.lock().unwrap();
+            tx.closed = true;
+            if let Some(waker) = tx.waker.take() {
+                waker.wake();
+            }
+        }
+    }
+    impl async_std::io::Read for MemoryStream {
+        fn poll_read(
+            self: Pin<&mut Self>,
+            cx: &mut Context,
+            buf: &mut [u8],
+        ) -> Poll<io::Result<usize>> {
+            let mut rx = self.rx.lock().unwrap();
+            if rx.buf.is_empty() && !rx.closed {
+                rx.waker = Some(cx.waker().clone());
+                return Poll::Pending;
+            }
+            let size = buf.len().min(rx.buf.len());
+            for (b, x) in buf.iter_mut().zip(rx.buf.drain(..size)) {
+                *b = x;
+            }
+            Poll::Ready(Ok(size))
+        }
+    }
+    impl async_std::io::Write for MemoryStream {
+        fn poll_write(
+            self: Pin<&mut Self>,
+            _cx: &mut Context,
+            buf: &[u8],
+        ) -> Poll<io::Result<usize>> {
+            let mut tx = self.tx.lock().unwrap();
+            if tx.closed {
+                return Poll::Ready(Err(io::ErrorKind::BrokenPipe.into()));
+            }
+            tx.buf.extend(buf);
+            if let Some(waker) = tx.waker.take() {
+                waker.wake();
+            }
+            Poll::Ready(Ok(buf.len()))
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn poll_close(self: Pin<&mut Self>, _cx: &mut Context) -> Poll<io::Result<()>> {
+            self.close_tx();
+            Poll::Ready(Ok(()))
+        }
+    }
+    impl ClientStream for MemoryStream {
+        fn peer_addr(&self) -> io::Result<SocketAddr> {
+            Err(io::ErrorKind::NotConnected.into())
+        }
+
+        fn local_addr(&self) -> io::Result<SocketAddr> {
+            Err(io::ErrorKind::NotConnected.into())
+        }
+    }
+    impl BackendStream for MemoryStream {
+        fn shutdown_write(&self) -> io::Result<()> {
+            self.close_tx();
+            Ok(())
+        }
+    }
+    impl Drop for MemoryStream {
+        fn drop(&mut self) {
+            self.close_tx();
+        }
+    }
+
+    /// Makes a channel that is polled (or inspected) by the test itself, and its client.
+    async fn new_channel(
+        real_server_addr: SocketAddr,
+        config: ChannelConfig,
+    ) -> (TcpStream, ProxyChannel) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (stream, _) = listener.accept().await.unwrap();
+        let channel = ProxyChannel::new(
+            stream,
+            real_server_addr,
+            config,
+            Registry::default(),
+            None,
+            Registry::default().register(0),
+            ParkedBackends::default(),
+        );
+        (client, channel)
+    }
+
+    async fn connect_client(config: ChannelConfig) -> TcpStream {
+        let backend = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let backend_addr = backend.local_addr().unwrap();
+        async_std::task::spawn(async move {
+            let (mut stream, _) = backend.accept().await.unwrap();
+            let _ = stream.read_to_end(&mut Vec::new()).await;
+        });
+        connect_client_to(backend_addr, config).await
+    }
+
+    async fn connect_client_to(backend_addr: SocketAddr, config: ChannelConfig) -> TcpStream {
+        let mut client = spawn_channel(backend_addr, config).await;
+        client.write_all(HANDSHAKE_REQUEST).await.unwrap();
+        let response = read_response(&mut client).await;
+        assert!(response.starts_with(b"HTTP/1.1 101 "));
+        client
+    }
+
+    fn masked_frame(first_byte: u8, payload: &[u8]) -> Vec<u8> {
+        let mask = [1, 2, 3, 4];
+        let mut frame = vec![first_byte];
+        if payload.len() < 126 {
+            frame.push(0x80 | payload.len() as u8);
+        } else {
+            frame.push(0x80 | 126);
+            frame.extend_from_slice(&(payload.len() as u16).to_be_bytes());
+        }
+        frame.extend_from_slice(&mask);
+        frame.extend(payload.iter().zip(mask.iter().cycle()).map(|(b, m)| b ^ m));
+        frame
+    }
+
+    async fn relay_reply(frame_type: FrameType, client_opcodes: &[u8]) -> [u8; 4] {
+        let backend = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let backend_addr = backend.local_addr().unwrap();
+        let expected_len = client_opcodes.len();
+        async_std::task::spawn(async move {
+            let (mut stream, _) = backend.accept().await.unwrap();
+            let mut buf = vec![0; expected_len];
+            stream.read_exact(&mut buf).await.unwrap();
+            stream.write_all(b"ok").await.unwrap();
+            let _ = stream.read(&mut [0; 1]).await;
+        });
+
+        let config = ChannelConfig {
+            frame_type,
+            ..Default::default()
+        };
+        let mut client = spawn_channel(backend_addr, config).await;
+        client.write_all(HANDSHAKE_REQUEST).await.unwrap();
+        read_response(&mut client).await;
+        for (i, &opcode) in client_opcodes.iter().enumerate() {
+            client
+                .write_all(&masked_frame(0x80 | opcode, &[b'0' + i as u8]))
+                .await
+                .unwrap();
+        }
+
+        let mut reply = [0; 4];
+        client.read_exact(&mut reply).await.unwrap();
+        reply
+    }
+
+    #[test]
+    fn frame_type_works() {
+        async_std::task::block_on(async {
+            let (text, binary) = (0x1, 0x2);
+            let reply = relay_reply(FrameType::Binary, &[text]).await;
+            assert_eq!(reply, *b"\x82\x02ok");
+
+            let reply = relay_reply(FrameType::Text, &[binary]).await;
+            assert_eq!(reply, *b"\x81\x02ok");
+
+            let reply = relay_reply(FrameType::Mirror, &[binary, text]).await;
+            assert_eq!(reply, *b"\x81\x02ok");
+
+            let reply = relay_reply(FrameType::Mirror, &[text, binary]).await;
+            assert_eq!(reply, *b"\x82\x02ok");
+        });
+    }
+
+    #[test]
+    fn unavailable_response_has_body() {
+        async_std::task::block_on(async {
+            let backend = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let backend_addr = backend.local_addr().unwrap();
+            drop(backend);
+
+            let config = ChannelConfig {
+                unavailable_body: Some("backend is down".to_owned()),
+                ..Default::default()
+            };
+            let mut client = spawn_channel(backend_addr, config).await;
+            client.write_all(HANDSHAKE_REQUEST).await.unwrap();
+            let mut response = String::new();
+            client.read_to_string(&mut response).await.unwrap();
+            assert!(response.starts_with("HTTP/1.1 503 "));
+            assert!(response.contains("\r\nContent-Length: 15\r\n"));
+            assert!(response.ends_with("\r\n\r\nbackend is down"));
+        });
+    }
+
+    #[test]
+    fn handshake_errors_have_distinct_kinds() {
+        async_std::task::block_on(async {
+            let backend = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let backend_addr = backend.local_addr().unwrap();
+            drop(backend);
+
+            for (request, kind) in [
+                (HANDSHAKE_REQUEST, ErrorKind::BackendUnavailable),
+                (b"GET / HTTP/1.1\r\n\r\n", ErrorKind::HandshakeFailed),
+            ] {
+                let (mut client, channel) =
+                    new_channel(backend_addr, ChannelConfig::default()).await;
+                client.write_all(request).await.unwrap();
+                let e = channel.await.unwrap_err();
+                assert_eq!(*e.kind(), kind);
+            }
+        });
+    }
+
+    #[test]
+    fn plain_http_request_gets_426() {
+        async_std::task::block_on(async {
+            let mut client =
+                spawn_channel("127.0.0.1:1".parse().unwrap(), Default::default()).await;
+            client
+                .write_all(b"GET / HTTP/1.1\r\nHost: example.com\r\n\r\n")
+                .await
+                .unwrap();
+            let mut response = String::new();
+            client.read_to_string(&mut response).await.unwrap();
+            assert!(response.starts_with("HTTP/1.1 426 Upgrade Required\r\n"));
+            assert!(response.contains("\r\nUpgrade: websocket\r\n"));
+            assert!(response.ends_with(
+                "\r\n\r\nThis is a WebSocket endpoint; connect with a WebSocket client.\n"
+            ));
+        });
+    }
+
+    #[test]
+    fn protocol_error_closes_with_1002() {
+        async_std::task::block_on(async {
+            let mut client = connect_client(ChannelConfig::default()).await;
+            client
+                .write_all(&[0x82, 0xFF, 0x80, 0, 0, 0, 0, 0, 0, 0, 1, 2, 3, 4])
+                .await
+                .unwrap();
+            let mut reply = [0; 4];
+            client.read_exact(&mut reply).await.unwrap();
+            assert_eq!(reply, [0x88, 0x02, 0x03, 0xEA]);
+        });
+    }
+
+    #[test]
+    fn too_large_ping_closes_with_1002() {
+        async_std::task::block_on(async {
+            let config = ChannelConfig {
+                buffer_size: Some(125),
+                ..Default::default()
+            };
+            let mut client = connect_client(config).await;
+            client
+                .write_all(&masked_frame(0x89, &[0; 126]))
+                .await
+                .unwrap();
+            let mut reply = [0; 4];
+            client.read_exact(&mut reply).await.unwrap();
+            assert_eq!(reply, [0x88, 0x02, 0x03, 0xEA]);
+        });
+    }
+
+    #[test]
+    fn unmasked_frame_closes_with_1002() {
+        async_std::task::block_on(async {
+            let mut client = connect_client(ChannelConfig::default()).await;
+            client.write_all(b"\x82\x03foo").await.unwrap();
+            let mut reply = [0; 4];
+            client.read_exact(&mut reply).await.unwrap();
+            assert_eq!(reply, [0x88, 0x02, 0x03, 0xEA]);
+        });
+    }
+
+    #[test]
+    fn invalid_utf8_closes_with_1007() {
+        async_std::task::block_on(async {
+            let backend = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let backend_addr = backend.local_addr().unwrap();
+            let received = async_std::task::spawn(async move {
+                let (mut stream, _) = backend.accept().await.unwrap();
+                let mut buf = Vec::new();
+                stream.read_to_end(&mut buf).await.unwrap();
+                buf
+            });
+
+            // "é" (0xC3 0xA9) split across two fragments is valid
+            let mut client = connect_client_to(backend_addr, ChannelConfig::default()).await;
+            let frames = [masked_frame(0x01, b"caf\xC3"), masked_frame(0x80, b"\xA9")].concat();
+            client.write_all(&frames).await.unwrap();
+
+            // A message ending with a truncated "é" is not
+            client
+                .write_all(&masked_frame(0x81, b"caf\xC3"))
+                .await
+                .unwrap();
+            let mut reply = [0; 4];
+            client.read_exact(&mut reply).await.unwrap();
+            assert_eq!(reply, [0x88, 0x02, 0x03, 0xEF]);
+            assert!(received.await.starts_with("caf\u{e9}".as_bytes()));
+        });
+    }
+
+    #[test]
+    fn max_message_size_works() {
+        async_std::task::block_on(async {
+            let config = ChannelConfig {
+                max_message_size: Some(1024),
+                ..Default::default()
+            };
+            let mut client = connect_client(config).await;
+
+            // 2 KiB in two fragments: the second one makes the message too big
+            let frames = [
+                masked_frame(0x02, &[0; 1024]),
+                masked_frame(0x80, &[0; 1024]),
+            ]
+            .concat();
+            client.write_all(&frames).await.unwrap();
+            let mut reply = [0; 4];
+            client.read_exact(&mut reply).await.unwrap();
+            assert_eq!(reply, [0x88, 0x02, 0x03, 0xF1]);
+        });
+    }
+
+    #[test]
+    fn overflow_notice_works() {
+        async_std::task::block_on(async {
+            let config = ChannelConfig {
+                max_message_size: Some(1024),
+                overflow_notice: Some(b"too big".to_vec()),
+                ..Default::default()
+            };
+            let mut client = connect_client(config).await;
+            client
+                .write_all(&masked_frame(0x82, &[0; 2048]))
+                .await
+                .unwrap();
+            let mut reply = [0; 13];
+            client.read_exact(&mut reply).await.unwrap();
+            assert_eq!(&reply[..9], b"\x82\x07too big");
+            assert_eq!(&reply[9..], [0x88, 0x02, 0x03, 0xF1]);
+        });
+    }
+
+    #[test]
+    fn accepted_frame_types_works() {
+        async_std::task::block_on(async {
+            // Rejected: the frame is not relayed
+            let config = ChannelConfig {
+                accepted_frame_types: AcceptedFrameTypes::Binary,
+                ..Default::default()
+            };
+            let mut client = connect_client(config).await;
+            client.write_all(&masked_frame(0x81, b"foo")).await.unwrap();
+            let reason = b"unsupported frame type";
+            let mut reply = vec![0; 4 + reason.len()];
+            client.read_exact(&mut reply).await.unwrap();
+            assert_eq!(reply[..4], [0x88, 2 + reason.len() as u8, 0x03, 0xEB]);
+            assert_eq!(&reply[4..], reason);
+
+            // Accepted
+            let backend = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let backend_addr = backend.local_addr().unwrap();
+            let received = async_std::task::spawn(async move {
+                let (mut stream, _) = backend.accept().await.unwrap();
+                let mut buf = [0; 3];
+                stream.read_exact(&mut buf).await.unwrap();
+                buf
+            });
+            let mut client = connect_client_to(backend_addr, ChannelConfig::default()).await;
+            client.write_all(&masked_frame(0x81, b"foo")).await.unwrap();
+            assert_eq!(&received.await, b"foo");
+        });
+    }
+
+    #[test]
+    fn client_heartbeat_works() {
+        async_std::task::block_on(async {
+            let config = ChannelConfig {
+                client_heartbeat: Some((b"hb".to_vec(), Duration::from_millis(50))),
+                ..Default::default()
+            };
+            let mut client = connect_client(config).await;
+            let start = std::time::Instant::now();
+            for _ in 0..2 {
+                let mut frame = [0; 4];
+                client.read_exact(&mut frame).await.unwrap();
+                assert_eq!(&frame, b"\x82\x02hb");
+            }
+            assert!(start.elapsed() >= Duration::from_millis(100));
+        });
+    }
+
+    #[test]
+    fn keepalive_works() {
+        async_std::task::block_on(async {
+            let config = ChannelConfig {
+                keepalive_interval: Some(Duration::from_millis(50)),
+                ..Default::default()
+            };
+
+            // Answered Pings keep the connection open
+            let mut client = connect_client(config.clone()).await;
+            for _ in 0..2 {
+                let mut frame = [0; 2];
+                client.read_exact(&mut frame).await.unwrap();
+                assert_eq!(frame, [0x89, 0x00]);
+                client.write_all(&[0x8A, 0x80, 0, 0, 0, 0]).await.unwrap();
+            }
+
+            // An unanswered Ping closes the connection with 1001
+            let mut client = connect_client(config).await;
+            let mut frame = [0; 2];
+            client.read_exact(&mut frame).await.unwrap();
+            assert_eq!(frame, [0x89, 0x00]);
+            let mut frame = [0; 21];
+            client.read_exact(&mut frame).await.unwrap();
+            assert_eq!(&frame[..4], [0x88, 19, 0x03, 0xE9]);
+            assert_eq!(&frame[4..], b"keepalive timeout");
+        });
+    }
+
+    #[test]
+    fn idle_timeout_works() {
+        async_std::task::block_on(async {
+            let config = ChannelConfig {
+                idle_timeout: Some(Duration::from_millis(100)),
+                ..Default::default()
+            };
+            let mut client = connect_client(config).await;
+            let start = std::time::Instant::now();
+            let mut frame = [0; 16];
+            client.read_exact(&mut frame).await.unwrap();
+            assert_eq!(&frame[..4], [0x88, 14, 0x03, 0xE9]);
+            assert_eq!(&frame[4..], b"idle timeout");
+            assert!(start.elapsed() >= Duration::from_millis(100));
+        });
+    }
+
+    #[test]
+    fn client_half_close_works() {
+        async_std::task::block_on(async {
+            let backend = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let backend_addr = backend.local_addr().unwrap();
+            async_std::task::spawn(async move {
+                let (mut stream, _) = backend.accept().await.unwrap();
+                let mut data = Vec::new();
+                stream.read_to_end(&mut data).await.unwrap();
+                assert_eq!(data, b"hi");
+                async_std::task::sleep(Duration::from_millis(50)).await;
+                stream.write_all(b"bye").await.unwrap();
+            });
+
+            let mut client = connect_client_to(backend_addr, ChannelConfig::default()).await;
+            client.write_all(&masked_frame(0x82, b"hi")).await.unwrap();
+            client.shutdown(Shutdown::Write).unwrap();
+
+            let mut frames = Vec::new();
+            client.read_to_end(&mut frames).await.unwrap();
+            assert_eq!(frames, b"\x82\x03bye\x88\x02\x03\xE8");
+        });
+    }
+
+    #[test]
+    fn poll_time_budget_works() {
+        async_std::task::block_on(async {
+            let config = ChannelConfig {
+                poll_time_budget: Some((Duration::from_micros(1), Duration::from_secs(60))),
+                close_over_poll_time_budget: true,
+                ..Default::default()
+            };
+            let mut client = connect_client(config).await;
+            let mut writer = client.clone();
+            async_std::task::spawn(async move {
+                let frame = masked_frame(0x82, b"x").repeat(1000);
+                while writer.write_all(&frame).await.is_ok() {}
+            });
+
+            let mut frame = [0; 29];
+            client.read_exact(&mut frame).await.unwrap();
+            assert_eq!(&frame[..4], [0x88, 27, 0x03, 0xF0]);
+            assert_eq!(&frame[4..], b"poll time budget exceeded");
+        });
+    }
+
+    #[test]
+    fn handshake_order_works() {
+        async_std::task::block_on(async {
+            for order in [HandshakeOrder::BackendFirst, HandshakeOrder::RespondFirst] {
+                let config = ChannelConfig {
+                    handshake_order: order,
+                    ..Default::default()
+                };
+                let mut client = connect_client(config).await;
+                client.write_all(&masked_frame(0x82, b"foo")).await.unwrap();
+                client.write_all(&[0x88, 0x80, 0, 0, 0, 0]).await.unwrap();
+                let mut close = [0; 2];
+                client.read_exact(&mut close).await.unwrap();
+                assert_eq!(close, [0x88, 0x00]);
+            }
+
+            // The real server is not reachable
+            let backend = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let backend_addr = backend.local_addr().unwrap();
+            drop(backend);
+
+            let mut client = spawn_channel(backend_addr, ChannelConfig::default()).await;
+            client.write_all(HANDSHAKE_REQUEST).await.unwrap();
+            let response = read_response(&mut client).await;
+            assert!(response.starts_with(b"HTTP/1.1 503 "));
+
+            let config = ChannelConfig {
+                handshake_order: HandshakeOrder::RespondFirst,
+                ..Default::default()
+            };
+            let mut client = connect_client_to(backend_addr, config).await;
+            let mut close = [0; 27];
+            client.read_exact(&mut close).await.unwrap();
+            assert_eq!(&close[..4], [0x88, 25, 0x03, 0xF3]);
+            assert_eq!(&close[4..], b"real server unavailable");
+        });
+    }
+
+    #[test]
+    fn in_memory_stream_works() {
+        async_std::task::block_on(async {
+            let (mut client, stream) = MemoryStream::pair();
+            let (mut backend, real_stream) = MemoryStream::pair();
+            let real_stream = Mutex::new(Some(real_stream));
+            let connector = Connector::new(move |_, _| {
+                let stream = real_stream.lock().unwrap().take();
+                Box::pin(async move { stream.ok_or_else(|| io::ErrorKind::NotFound.into()) })
+            });
+            let channel = async_std::task::spawn(ProxyChannel::with_connector(
+                stream,
+                "127.0.0.1:1".parse().unwrap(),
+                ChannelConfig::default(),
+                Registry::default(),
+                None,
+                Registry::default().register(0),
+                ParkedBackends::default(),
+                connector,
+            ));
+
+            client.write_all(HANDSHAKE_REQUEST).await.unwrap();
+            let response = read_response(&mut client).await;
+            assert!(response.starts_with(b"HTTP/1.1 101 "));
+
+            // Client to backend
+            client.write_all(&masked_frame(0x82, b"foo")).await.unwrap();
+            let mut buf = [0; 3];
+            backend.read_exact(&mut buf).await.unwrap();
+            assert_eq!(&buf, b"foo");
+
+            // Backend to client
+            backend.write_all(b"bar").await.unwrap();
+            let mut frame = [0; 5];
+            client.read_exact(&mut frame).await.unwrap();
+            assert_eq!(frame, [0x82, 0x03, b'b', b'a', b'r']);
+
+            let close = masked_frame(0x88, &[0x03, 0xE8]);
+            client.write_all(&close).await.unwrap();
+            let mut reply = [0; 4];
+            client.read_exact(&mut reply).await.unwrap();
+            assert_eq!(reply, [0x88, 0x02, 0x03, 0xE8]);
+            assert!(channel.await.is_ok());
+        });
+    }
+
+    #[test]
+    fn max_pongs_works() {
+        async_std::task::block_on(async {
+            for close in [false, true] {
+                let config = ChannelConfig {
+                    max_pongs: Some(2),
+                    close_over_max_pongs: close,
+                    ..Default::default()
+                };
+                let mut client = connect_client(config).await;
+                for _ in 0..2 {
+                    client.write_all(&masked_frame(0x89, b"hi")).await.unwrap();
+                    let mut pong = [0; 4];
+                    client.read_exact(&mut pong).await.unwrap();
+                    assert_eq!(pong, *b"\x8A\x02hi");
+                }
+
+                client.write_all(&masked_frame(0x89, b"hi")).await.unwrap();
+                if close {
+                    let mut frame = [0; 18];
+                    client.read_exact(&mut frame).await.unwrap();
+                    assert_eq!(&frame[..4], [0x88, 16, 0x03, 0xF0]);
+                    assert_eq!(&frame[4..], b"too many pings");
+                } else {
+                    // The next frame is the reply to the Close frame (not a Pong)
+                    client
+                        .write_all(&masked_frame(0x88, &[0x03, 0xE8]))
+                        .await
+                        .unwrap();
+                    let mut frame = [0; 4];
+                    client.read_exact(&mut frame).await.unwrap();
+                    assert_eq!(frame, [0x88, 0x02, 0x03, 0xE8]);
+                }
+            }
+        });
+    }
+
+    #[test]
+    fn subprotocol_negotiation_works() {
+        async_std::task::block_on(async {
+            let backend = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let backend_addr = backend.local_addr().unwrap();
+            async_std::task::spawn(async move {
+                loop {
+                    let (mut stream, _) = backend.accept().await.unwrap();
+                    async_std::task::spawn(async move {
+                        let _ = stream.read_to_end(&mut Vec::new()).await;
+                    });
+                }
+            });
+
+            let cases = [
+                (
+                    false,
+                    Some("foo, graphql-ws, mqtt"),
+                    "101",
+                    Some("graphql-ws"),
+                ),
+                (false, Some("foo"), "101", None),
+                (true, Some("foo"), "400", None),
+                (true, None, "101", None),
+            ];
+            for (require, offered, status, selected) in cases {
+                let config = ChannelConfig {
+                    subprotocols: vec!["mqtt".to_owned(), "graphql-ws".to_owned()],
+                    require_subprotocol: require,
+                    ..Default::default()
+                };
+                let mut client = spawn_channel(backend_addr, config).await;
+                let mut request = String::from_utf8(HANDSHAKE_REQUEST.to_vec()).unwrap();
+                if let Some(offered) = offered {
+                    let header = format!("Sec-WebSocket-Protocol: {}\r\n\r\n", offered);
+                    request = request.replacen("\r\n\r\n", &format!("\r\n{}", header), 1);
+                }
+                client.write_all(request.as_bytes()).await.unwrap();
+                let response = String::from_utf8(read_response(&mut client).await).unwrap();
+                assert!(response.starts_with(&format!("HTTP/1.1 {} ", status)));
+
+                // The client's order of preference is respected
+                let header = selected.map(|p| format!("Sec-WebSocket-Protocol: {}\r\n", p));
+                match header {
+                    Some(header) => assert!(response.contains(&header), "{}", response),
+                    None => assert!(!response.contains("Sec-WebSocket-Protocol")),
+                }
+            }
+        });
+    }
+
+    #[test]
+    fn origin_allowlist_works() {
+        async_std::task::block_on(async {
+            let backend = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let backend_addr = backend.local_addr().unwrap();
+            let config = ChannelConfig {
+                allowed_origins: vec![
+                    "https://example.com".to_owned(),
+                    "https://*.example.net".to_owned(),
+                ],
+                ..Default::default()
+            };
+            let cases = [
+                (Some("https://example.com"), "101"),
+                (Some("https://app.example.net"), "101"),
+                (Some("https://evil.example"), "403"),
+                (None, "403"),
+            ];
+            for (origin, status) in cases {
+                let mut client = spawn_channel(backend_addr, config.clone()).await;
+                let mut request = String::from_utf8(HANDSHAKE_REQUEST.to_vec()).unwrap();
+                if let Some(origin) = origin {
+                    let header = format!("\r\nOrigin: {}\r\n\r\n", origin);
+                    request = request.replacen("\r\n\r\n", &header, 1);
+                }
+                client.write_all(request.as_bytes()).await.unwrap();
+                let response = read_response(&mut client).await;
+                let status_line = format!("HTTP/1.1 {} ", status);
+                assert!(response.starts_with(status_line.as_bytes()), "{:?}", origin);
+            }
+        });
+    }
+
+    #[test]
+    fn routing_works() {
+        async_std::task::block_on(async {
+            let db = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let fallback = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let routes = vec![
+                Route::new("/db", db.local_addr().unwrap()),
+                Route::new("/", fallback.local_addr().unwrap()),
+            ];
+            let unused_addr = "127.0.0.1:1".parse().unwrap();
+
+            for (target, routes, status, backend) in [
+                ("/db/main", routes.clone(), "101", Some(&db)),
+                ("/cache", routes.clone(), "101", Some(&fallback)),
+                ("/cache", routes[..1].to_vec(), "404", None),
+            ] {
+                let config = ChannelConfig {
+                    routes,
+                    ..Default::default()
+                };
+                let mut client = spawn_channel(unused_addr, config).await;
+                let request = String::from_utf8(HANDSHAKE_REQUEST.to_vec())
+                    .unwrap()
+                    .replacen("GET / ", &format!("GET {} ", target), 1);
+                client.write_all(request.as_bytes()).await.unwrap();
+                let response = read_response(&mut client).await;
+                let status_line = format!("HTTP/1.1 {} ", status);
+                assert!(response.starts_with(status_line.as_bytes()), "{}", target);
+                if let Some(backend) = backend {
+                    let accept = async_std::io::timeout(Duration::from_secs(1), backend.accept());
+                    assert!(accept.await.is_ok());
+                }
+            }
+
+            // Invalid requests are rejected as such even if no route matches
+            let config = ChannelConfig {
+                routes: routes[..1].to_vec(),
+                ..Default::default()
+            };
+            let mut client = spawn_channel(unused_addr, config).await;
+            let request = String::from_utf8(HANDSHAKE_REQUEST.to_vec())
+                .unwrap()
+                .replacen("GET / HTTP/1.1", "GET /cache HTTP/1.0", 1);
+            client.write_all(request.as_bytes()).await.unwrap();
+            let response = read_response(&mut client).await;
+            assert!(response.starts_with(b"HTTP/1.1 400 "));
+        });
+    }
+
+    #[test]
+    fn max_uri_length_works() {
+        async_std::task::block_on(async {
+            let config = ChannelConfig {
+                max_uri_length: Some(16),
+                ..Default::default()
+            };
+            for (target, status) in [("/0123456789abcde", "101"), ("/0123456789abcdef", "414")] {
+                let backend = TcpListener::bind("127.0.0.1:0").await.unwrap();
+                let backend_addr = backend.local_addr().unwrap();
+                let mut client = spawn_channel(backend_addr, config.clone()).await;
+                let request = String::from_utf8(HANDSHAKE_REQUEST.to_vec())
+                    .unwrap()
+                    .replacen("GET / ", &format!("GET {} ", target), 1);
+                client.write_all(request.as_bytes()).await.unwrap();
+                let response = read_response(&mut client).await;
+                let status_line = format!("HTTP/1.1 {} ", status);
+                assert!(response.starts_with(status_line.as_bytes()));
+            }
+        });
+    }
+
+    #[test]
+    fn validate_only_works() {
+        async_std::task::block_on(async {
+            let config = ChannelConfig {
+                validate_only: true,
+                ..Default::default()
+            };
+            // The real server is never connected
+            let backend_addr = "127.0.0.1:1".parse().unwrap();
+
+            // Valid: a fragmented message with an interleaved Ping, followed by a Close
+            let mut client = connect_client_to(backend_addr, config.clone()).await;
+            let frames = [
+                masked_frame(0x01, "h\u{e9}".as_bytes()),
+                masked_frame(0x89, b""),
+                masked_frame(0x80, b"llo"),
+                masked_frame(0x88, &[0x03, 0xE8]),
+            ]
+            .concat();
+            client.write_all(&frames).await.unwrap();
+            let mut reply = [0; 6];
+            client.read_exact(&mut reply).await.unwrap();
+            assert_eq!(reply, [0x8A, 0x00, 0x88, 0x02, 0x03, 0xE8]);
+
+            let invalid_frames: [(Vec<u8>, u16); 7] = [
+                (vec![0x82, 0x01, b'x'], 1002),
+                (masked_frame(0xC2, b"x"), 1002),
+                (masked_frame(0x09, b""), 1002),
+                (masked_frame(0x89, &[0; 126]), 1002),
+                (masked_frame(0x80, b"x"), 1002),
+                (masked_frame(0x81, &[0xFF]), 1007),
+                (masked_frame(0x88, &[0x03, 0xED]), 1002),
+            ];
+            for (frame, code) in invalid_frames {
+                let mut client = connect_client_to(backend_addr, config.clone()).await;
+                client.write_all(&frame).await.unwrap();
+                let mut reply = [0; 4];
+                client.read_exact(&mut reply).await.unwrap();
+                assert_eq!(reply[..2], [0x88, 0x02]);
+                assert_eq!(u16::from_be_bytes([reply[2], reply[3]]), code);
+            }
+        });
+    }
+
+    #[test]
+    fn app_handshake_works() {
+        async_std::task::block_on(async {
+            let config = ChannelConfig {
+                app_handshake_pattern: Some(Regex::new("^HELLO v[0-9]+$").unwrap()),
+                ..Default::default()
+            };
+
+            // Matching: the handshake frame is consumed and later frames are relayed
+            let backend = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let backend_addr = backend.local_addr().unwrap();
+            async_std::task::spawn(async move {
+                let (mut stream, _) = backend.accept().await.unwrap();
+                let mut buf = [0; 4];
+                stream.read_exact(&mut buf).await.unwrap();
+                stream.write_all(&buf).await.unwrap();
+                let _ = stream.read(&mut [0; 1]).await;
+            });
+            let mut client = spawn_channel(backend_addr, config.clone()).await;
+            client.write_all(HANDSHAKE_REQUEST).await.unwrap();
+            let response = read_response(&mut client).await;
+            assert!(response.starts_with(b"HTTP/1.1 101 "));
+            client
+                .write_all(&masked_frame(0x82, b"HELLO v1"))
+                .await
+                .unwrap();
+            client
+                .write_all(&masked_frame(0x82, b"data"))
+                .await
+                .unwrap();
+            let mut reply = [0; 6];
+            client.read_exact(&mut reply).await.unwrap();
+            assert_eq!(reply, *b"\x82\x04data");
+
+            // Not matching
+            let mut client = connect_client(config).await;
+            client
+                .write_all(&masked_frame(0x82, b"HELLO"))
+                .await
+                .unwrap();
+            let reason = b"application handshake rejected";
+            let mut reply = vec![0; 4 + reason.len()];
+            client.read_exact(&mut reply).await.unwrap();
+            assert_eq!(reply[..4], [0x88, 2 + reason.len() as u8, 0x03, 0xF0]);
+            assert_eq!(&reply[4..], reason);
+        });
+    }
+
+    #[test]
+    fn duplicate_session_closes_older_channel() {
+        async_std::task::block_on(async {
+            let backend = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let backend_addr = backend.local_addr().unwrap();
+            async_std::task::spawn(async move {
+                loop {
+                    let (mut stream, _) = backend.accept().await.unwrap();
+                    async_std::task::spawn(async move {
+                        let _ = stream.read_to_end(&mut Vec::new()).await;
+                    });
+                }
+            });
+
+            let config = ChannelConfig {
+                session_header: Some("X-Session-Id".to_owned()),
+                ..Default::default()
+            };
+            let sessions = Registry::default();
+            let request = [
+                &HANDSHAKE_REQUEST[..HANDSHAKE_REQUEST.len() - 2],
+                b"X-Session-Id: alice\r\n\r\n",
+            ]
+            .concat();
+            let mut clients = Vec::new();
+            for _ in 0..2 {
+                let mut client = spawn_channel_with(
+                    backend_addr,
+                    config.clone(),
+                    sessions.clone(),
+                    None,
+                    Registry::default().register(0),
+                    ParkedBackends::default(),
+                )
+                .await;
+                client.write_all(&request).await.unwrap();
+                let response = read_response(&mut client).await;
+                assert!(response.starts_with(b"HTTP/1.1 101 "));
+                clients.push(client);
+            }
+
+            let reason = b"session replaced";
+            let mut reply = vec![0; 4 + reason.len()];
+            clients[0].read_exact(&mut reply).await.unwrap();
+            assert_eq!(reply[..4], [0x88, 2 + reason.len() as u8, 0x0F, 0xA0]);
+            assert_eq!(&reply[4..], reason);
+        });
+    }
+
+    #[test]
+    fn resume_works() {
+        async_std::task::block_on(async {
+            let backend = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let backend_addr = backend.local_addr().unwrap();
+            let config = ChannelConfig {
+                resume_header: Some("X-Resume-Token".to_owned()),
+                resume_window: Some(Duration::from_millis(200)),
+                ..Default::default()
+            };
+            let parked = ParkedBackends::default();
+            let request = [
+                &HANDSHAKE_REQUEST[..HANDSHAKE_REQUEST.len() - 2],
+                b"X-Resume-Token: abc\r\n\r\n",
+            ]
+            .concat();
+            let connect = || async {
+                let mut client = spawn_channel_with(
+                    backend_addr,
+                    config.clone(),
+                    Registry::default(),
+                    None,
+                    Registry::default().register(0),
+                    parked.clone(),
+                )
+                .await;
+                client.write_all(&request).await.unwrap();
+                let response = read_response(&mut client).await;
+                assert!(response.starts_with(b"HTTP/1.1 101 "));
+                client
+            };
+
+            let mut client = connect().await;
+            let (mut backend_stream, _) = backend.accept().await.unwrap();
+            client.write_all(&masked_frame(0x82, b"one")).await.unwrap();
+            let mut buf = [0; 3];
+            backend_stream.read_exact(&mut buf).await.unwrap();
+            assert_eq!(&buf, b"one");
+
+            // The client disappears, and the real server sends data during the gap
+            drop(client);
+            async_std::task::sleep(Duration::from_millis(50)).await;
+            backend_stream.write_all(b"two").await.unwrap();
+
+            let mut client = connect().await;
+            let mut reply = [0; 5];
+            client.read_exact(&mut reply).await.unwrap();
+            assert_eq!(&reply, b"\x82\x03two");
+            client
+                .write_all(&masked_frame(0x82, b"three"))
+                .await
+                .unwrap();
+            let mut buf = [0; 5];
+            backend_stream.read_exact(&mut buf).await.unwrap();
+            assert_eq!(&buf, b"three");
+            let accept = async_std::io::timeout(Duration::from_millis(50), backend.accept());
+            assert!(accept.await.is_err());
+
+            // Not resumed within the window
+            drop(client);
+            let n = backend_stream.read(&mut buf).await.unwrap();
+            assert_eq!(n, 0);
+        });
+    }
+
+    #[test]
+    fn close_webhook_works() {
+        async_std::task::block_on(async {
+            let hook = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let url = format!("http://{}/close", hook.local_addr().unwrap());
+            let received = async_std::task::spawn(async move {
+                let (mut stream, _) = hook.accept().await.unwrap();
+                let mut request = Vec::new();
+                let mut buf = [0; 1024];
+                while !request.ends_with(b"}") {
+                    let size = stream.read(&mut buf).await.unwrap();
+                    assert_ne!(size, 0);
+                    request.extend_from_slice(&buf[..size]);
+                }
+                stream
+                    .write_all(b"HTTP/1.1 204 No Content\r\n\r\n")
+                    .await
+                    .unwrap();
+                String::from_utf8(request).unwrap()
+            });
+
+            // The real server closes the connection immediately
+            let backend = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let backend_addr = backend.local_addr().unwrap();
+            async_std::task::spawn(async move {
+                let _ = backend.accept().await.unwrap();
+            });
+
+            let webhook = CloseWebhook::new(&url).unwrap();
+            let mut client = spawn_channel_with(
+                backend_addr,
+                ChannelConfig::default(),
+                Registry::default(),
+                Some(webhook),
+                Registry::default().register(0),
+                ParkedBackends::default(),
+            )
+            .await;
+            client.write_all(HANDSHAKE_REQUEST).await.unwrap();
+            read_response(&mut client).await;
+            let mut reply = [0; 4];
+            client.read_exact(&mut reply).await.unwrap();
+            assert_eq!(reply, [0x88, 0x02, 0x03, 0xE8]);
+            client
+                .write_all(&masked_frame(0x88, &[0x03, 0xE8]))
+                .await
+                .unwrap();
+
+            let request = received.await;
+            assert!(request.starts_with("POST /close HTTP/1.1\r\n"));
+            assert!(request.contains("\r\nContent-Type: application/json\r\n"));
+            assert!(request.contains(&format!(r#""backend":"{}""#, backend_addr)));
+            assert!(request.contains(r#""close_code":1000"#));
+        });
+    }
+
+    #[test]
+    fn deadline_header_works() {
+        async_std::task::block_on(async {
+            let backend = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let backend_addr = backend.local_addr().unwrap();
+            async_std::task::spawn(async move {
+                loop {
+                    let (mut stream, _) = backend.accept().await.unwrap();
+                    async_std::task::spawn(async move {
+                        let _ = stream.read_to_end(&mut Vec::new()).await;
+                    });
+                }
+            });
+            let config = ChannelConfig {
+                deadline_header: Some("X-Deadline".to_owned()),
+                ..Default::default()
+            };
+            let with_deadline = |value: String| {
+                let field = format!("X-Deadline: {}\r\n\r\n", value);
+                [
+                    &HANDSHAKE_REQUEST[..HANDSHAKE_REQUEST.len() - 2],
+                    field.as_bytes(),
+                ]
+                .concat()
+            };
+
+            let deadline = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap()
+                .as_secs()
+                + 1;
+            let mut client = spawn_channel(backend_addr, config.clone()).await;
+            client
+                .write_all(&with_deadline(deadline.to_string()))
+                .await
+                .unwrap();
+            let response = read_response(&mut client).await;
+            assert!(response.starts_with(b"HTTP/1.1 101 "));
+
+            let reason = b"connection deadline reached";
+            let mut reply = vec![0; 4 + reason.len()];
+            client.read_exact(&mut reply).await.unwrap();
+            let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+            assert!(now >= Duration::from_secs(deadline));
+            assert!(now < Duration::from_secs(deadline + 1));
+            assert_eq!(reply[..4], [0x88, 2 + reason.len() as u8, 0x03, 0xE9]);
+            assert_eq!(&reply[4..], reason);
+
+            // Malformed
+            let mut client = spawn_channel(backend_addr, config).await;
+            client
+                .write_all(&with_deadline("tomorrow".to_owned()))
+                .await
+                .unwrap();
+            let response = read_response(&mut client).await;
+            assert!(response.starts_with(b"HTTP/1.1 400 "));
+        });
+    }
+
+    #[test]
+    fn handshake_response_is_written_across_polls() {
+        async_std::task::block_on(async {
+            let backend = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let backend_addr = backend.local_addr().unwrap();
+            async_std::task::spawn(async move {
+                let (mut stream, _) = backend.accept().await.unwrap();
+                stream.write_all(b"ok").await.unwrap();
+                let _ = stream.read_to_end(&mut Vec::new()).await;
+            });
+
+            // The response is larger than the write buffer and the client's receive buffer
+            let server = "x".repeat(DEFAULT_BUF_SIZE * 4);
+            let config = ChannelConfig {
+                server_header: Some(server.clone()),
+                ..Default::default()
+            };
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let socket =
+                socket2::Socket::new(socket2::Domain::IPV4, socket2::Type::STREAM, None).unwrap();
+            socket.set_recv_buffer_size(1024).unwrap();
+            socket
+                .connect(&listener.local_addr().unwrap().into())
+                .unwrap();
+            let mut client = TcpStream::from(std::net::TcpStream::from(socket));
+            let (stream, _) = listener.accept().await.unwrap();
+            socket2::SockRef::from(&stream)
+                .set_send_buffer_size(1024)
+                .unwrap();
+            async_std::task::spawn(ProxyChannel::new(
+                stream,
+                backend_addr,
+                config,
+                Registry::default(),
+                None,
+                Registry::default().register(0),
+                ParkedBackends::default(),
+            ));
+
+            client.write_all(HANDSHAKE_REQUEST).await.unwrap();
+            async_std::task::sleep(Duration::from_millis(50)).await;
+            let response = read_response(&mut client).await;
+            assert!(response.starts_with(b"HTTP/1.1 101 "));
+            let field = format!("\r\nServer: {}\r\n", server);
+            assert!(response.windows(field.len()).any(|w| w == field.as_bytes()));
+
+            let mut reply = [0; 4];
+            client.read_exact(&mut reply).await.unwrap();
+            assert_eq!(reply, *b"\x82\x02ok");
+        });
+    }
+
+    #[test]
+    fn close_timeout_works() {
+        async_std::task::block_on(async {
+            // The real server keeps sending data
+            let backend = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let backend_addr = backend.local_addr().unwrap();
+            async_std::task::spawn(async move {
+                let (mut stream, _) = backend.accept().await.unwrap();
+                let _ = stream.write_all(&[0; 1024 * 1024]).await;
+            });
+
+            // The client sends a Close frame without reading anything, so the reply cannot be flushed
+            let config = ChannelConfig {
+                close_timeout: Some(Duration::from_millis(100)),
+                ..Default::default()
+            };
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let socket =
+                socket2::Socket::new(socket2::Domain::IPV4, socket2::Type::STREAM, None).unwrap();
+            socket.set_recv_buffer_size(1024).unwrap();
+            socket
+                .connect(&listener.local_addr().unwrap().into())
+                .unwrap();
+            let mut client = TcpStream::from(std::net::TcpStream::from(socket));
+            let (stream, _) = listener.accept().await.unwrap();
+            socket2::SockRef::from(&stream)
+                .set_send_buffer_size(1024)
+                .unwrap();
+            let channel = async_std::task::spawn(ProxyChannel::new(
+                stream,
+                backend_addr,
+                config,
+                Registry::default(),
+                None,
+                Registry::default().register(0),
+                ParkedBackends::default(),
+            ));
+            client.write_all(HANDSHAKE_REQUEST).await.unwrap();
+            async_std::task::sleep(Duration::from_millis(50)).await;
+            client
+                .write_all(&masked_frame(0x88, &[0x03, 0xE8]))
+                .await
+                .unwrap();
+
+            let result = async_std::future::timeout(Duration::from_secs(5), channel).await;
+            assert!(result.is_ok());
+            let mut received = Vec::new();
+            let _ = client.read_to_end(&mut received).await;
+            assert!(received.len() < 1024 * 1024);
+        });
+    }
+
+    #[test]
+    fn close_connection_works() {
+        async_std::task::block_on(async {
+            let backend = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let backend_addr = backend.local_addr().unwrap();
+            async_std::task::spawn(async move {
+                let (mut stream, _) = backend.accept().await.unwrap();
+                let _ = stream.read_to_end(&mut Vec::new()).await;
+            });
+
+            let connections = Registry::default();
+            let mut client = spawn_channel_with(
+                backend_addr,
+                ChannelConfig::default(),
+                Registry::default(),
+                None,
+                connections.register(7),
+                ParkedBackends::default(),
+            )
+            .await;
+            client.write_all(HANDSHAKE_REQUEST).await.unwrap();
+            read_response(&mut client).await;
+
+            assert!(!connections.signal(&8));
+            assert!(connections.signal(&7));
+            let reason = b"connection closed by server";
+            let mut reply = vec![0; 4 + reason.len()];
+            client.read_exact(&mut reply).await.unwrap();
+            assert_eq!(reply[..4], [0x88, 2 + reason.len() as u8, 0x03, 0xE9]);
+            assert_eq!(&reply[4..], reason);
+
+            client
+                .write_all(&masked_frame(0x88, &[0x03, 0xE9]))
+                .await
+                .unwrap();
+            let mut rest = Vec::new();
+            client.read_to_end(&mut rest).await.unwrap();
+            assert!(rest.is_empty());
+            assert!(!connections.signal(&7));
+        });
+    }
+
+    #[test]
+    fn backend_ready_probe_works() {
+        async_std::task::block_on(async {
+            let config = ChannelConfig {
+                backend_ready_probe: Some((b"PING\n".to_vec(), b"READY\n".to_vec())),
+                ..Default::default()
+            };
+            for (response, status) in [(&b"READY\n"[..], "101"), (b"NOT YET", "503")] {
+                let backend = TcpListener::bind("127.0.0.1:0").await.unwrap();
+                let backend_addr = backend.local_addr().unwrap();
+                async_std::task::spawn(async move {
+                    let (mut stream, _) = backend.accept().await.unwrap();
+                    let mut probe = [0; 5];
+                    stream.read_exact(&mut probe).await.unwrap();
+                    assert_eq!(&probe, b"PING\n");
+                    stream.write_all(response).await.unwrap();
+                    let _ = stream.read_to_end(&mut Vec::new()).await;
+                });
+
+                let mut client = spawn_channel(backend_addr, config.clone()).await;
+                client.write_all(HANDSHAKE_REQUEST).await.unwrap();
+                let response = read_response(&mut client).await;
+                let status_line = format!("HTTP/1.1 {} ", status);
+                assert!(response.starts_with(status_line.as_bytes()));
+            }
+        });
+    }
+
+    #[test]
+    fn capped_reader_works() {
+        let data = vec![1; 10_000];
+        let mut budget = 1000;
+        let mut rbuf = ReadBuf::new(vec![0; DEFAULT_BUF_SIZE]);
+        rbuf.fill(CappedReader::new(&data[..], &mut budget))
+            .unwrap();
+        assert_eq!(rbuf.len(), 1000);
+        assert!(rbuf.stream_state().would_block());
+        assert_eq!(budget, 0);
+    }
+
+    #[test]
+    fn max_read_per_poll_works() {
+        async_std::task::block_on(async {
+            let backend = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let backend_addr = backend.local_addr().unwrap();
+            let received = async_std::task::spawn(async move {
+                let (mut stream, _) = backend.accept().await.unwrap();
+                let mut buf = vec![0; 300];
+                stream.read_exact(&mut buf).await.unwrap();
+                buf
+            });
+
+            let config = ChannelConfig {
+                max_read_per_poll: Some(16),
+                ..Default::default()
+            };
+            let (mut client, stream) = MemoryStream::pair();
+            let mut channel = ProxyChannel::new(
+                stream,
+                backend_addr,
+                config,
+                Registry::default(),
+                None,
+                Registry::default().register(0),
+                ParkedBackends::default(),
+            );
+            client.write_all(HANDSHAKE_REQUEST).await.unwrap();
+            for i in 0..3 {
+                client
+                    .write_all(&masked_frame(0x82, &[i; 100]))
+                    .await
+                    .unwrap();
+            }
+
+            // Each poll reads at most 16 bytes, and yields even though more are pending
+            let unread = || client.tx.lock().unwrap().buf.len();
+            let mut cx = Context::from_waker(Waker::noop());
+            let mut yields = 0;
+            while unread() > 0 {
+                let before = unread();
+                assert!(Pin::new(&mut channel).poll(&mut cx).is_pending());
+                assert!(before - unread() <= 16);
+                if unread() > 0 {
+                    yields += 1;
+                }
+                async_std::task::sleep(Duration::from_millis(1)).await;
+            }
+            assert!(yields > 0);
+
+            async_std::task::spawn(channel);
+            let received = received.await;
+            assert!(received[..100].iter().all(|&b| b == 0));
+            assert!(received[200..].iter().all(|&b| b == 2));
+        });
+    }
+
+    #[test]
+    fn send_path_header_works() {
+        async_std::task::block_on(async {
+            let backend = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let backend_addr = backend.local_addr().unwrap();
+            let received = async_std::task::spawn(async move {
+                let (mut stream, _) = backend.accept().await.unwrap();
+                let mut buf = vec![0; 23];
+                stream.read_exact(&mut buf).await.unwrap();
+                buf
+            });
+
+            let config = ChannelConfig {
+                send_path_header: true,
+                ..Default::default()
+            };
+            let mut client = spawn_channel(backend_addr, config).await;
+            let request = String::from_utf8(HANDSHAKE_REQUEST.to_vec())
+                .unwrap()
+                .replacen("GET / ", "GET /chat?room=1 ", 1);
+            client.write_all(request.as_bytes()).await.unwrap();
+            let response = read_response(&mut client).await;
+            assert!(response.starts_with(b"HTTP/1.1 101 "));
+            client.write_all(&masked_frame(0x82, b"foo")).await.unwrap();
+
+            assert_eq!(received.await, b"Path: /chat?room=1\r\nfoo");
+        });
+    }
+
+    #[test]
+    fn proxy_v1_header_works() {
+        async_std::task::block_on(async {
+            let backend = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let backend_addr = backend.local_addr().unwrap();
+            let received = async_std::task::spawn(async move {
+                let (stream, _) = backend.accept().await.unwrap();
+                let mut buf = Vec::new();
+                stream.take(256).read_to_end(&mut buf).await.unwrap();
+                buf
+            });
+
+            let config = ChannelConfig {
+                client_address_header: ClientAddressHeader::ProxyV1,
+                ..Default::default()
+            };
+            let mut client = connect_client_to(backend_addr, config).await;
+            let expected = format!(
+                "PROXY TCP4 127.0.0.1 127.0.0.1 {} {}\r\nfoo",
+                client.local_addr().unwrap().port(),
+                client.peer_addr().unwrap().port()
+            );
+            client.write_all(&masked_frame(0x82, b"foo")).await.unwrap();
+            client.shutdown(Shutdown::Write).unwrap();
+            assert_eq!(received.await, expected.as_bytes());
+        });
+    }
+
+    #[test]
+    fn fragmented_message_relay_works() {
+        async_std::task::block_on(async {
+            let backend = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let backend_addr = backend.local_addr().unwrap();
+            let received = async_std::task::spawn(async move {
+                let (mut stream, _) = backend.accept().await.unwrap();
+                let mut buf = vec![0; 9];
+                stream.read_exact(&mut buf).await.unwrap();
+                buf
+            });
+
+            let mut client = connect_client_to(backend_addr, ChannelConfig::default()).await;
+            let frames = [
+                masked_frame(0x02, b"foo"),
+                masked_frame(0x89, b"ping"),
+                masked_frame(0x00, b"bar"),
+                masked_frame(0x80, b"baz"),
+            ]
+            .concat();
+            client.write_all(&frames).await.unwrap();
+
+            let mut pong = [0; 6];
+            client.read_exact(&mut pong).await.unwrap();
+            assert_eq!(pong, *b"\x8A\x04ping");
+            assert_eq!(received.await, b"foobarbaz");
+        });
+    }
+
+    #[test]
+    fn backend_lag_warning_works() {
+        async_std::task::block_on(async {
+            // The real server never reads
+            let backend = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let backend_addr = backend.local_addr().unwrap();
+            async_std::task::spawn(async move {
+                let (_stream, _) = backend.accept().await.unwrap();
+                async_std::task::sleep(Duration::from_secs(10)).await;
+            });
+
+            let config = ChannelConfig {
+                backend_lag_warning: Some(Duration::from_millis(100)),
+                ..Default::default()
+            };
+            let (mut client, mut channel) = new_channel(backend_addr, config).await;
+            async_std::task::spawn(async move {
+                client.write_all(HANDSHAKE_REQUEST).await.unwrap();
+                let frame = masked_frame(0x82, &[0; 60000]);
+                while client.write_all(&frame).await.is_ok() {}
+            });
+
+            for _ in 0..50 {
+                if channel.lag_warned {
+                    break;
+                }
+                let _ = async_std::future::timeout(Duration::from_millis(100), &mut channel).await;
+            }
+            assert!(channel.lag_warned);
+        });
+    }
+
+    #[test]
+    fn zero_length_frames_do_not_spin() {
+        async_std::task::block_on(async {
+            let backend = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let backend_addr = backend.local_addr().unwrap();
+            async_std::task::spawn(async move {
+                let (_stream, _) = backend.accept().await.unwrap();
+                async_std::task::sleep(Duration::from_secs(10)).await;
+            });
+
+            let (mut client, mut channel) =
+                new_channel(backend_addr, ChannelConfig::default()).await;
+            client.write_all(HANDSHAKE_REQUEST).await.unwrap();
+            for _ in 0..10 {
+                client.write_all(&masked_frame(0x82, b"")).await.unwrap();
+            }
+
+            // If `poll` looped busily, it would never return and the timeout would not fire
+            let result = async_std::future::timeout(Duration::from_millis(200), &mut channel).await;
+            assert!(result.is_err());
+            assert!(channel.handshake.done());
+            assert!(channel.closing.is_not_yet());
+            assert!(channel.ws_rbuf.is_empty());
+        });
+    }
+
+    #[test]
+    fn full_duplex_relay_works() {
+        const SIZE: usize = 4 * 1024 * 1024;
+        let pattern = |i: usize| (i % 251) as u8;
+        async_std::task::block_on(async {
+            let backend = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let backend_addr = backend.local_addr().unwrap();
+            let backend = async_std::task::spawn(async move {
+                let (mut stream, _) = backend.accept().await.unwrap();
+                let mut writer = stream.clone();
+                let write = async_std::task::spawn(async move {
+                    let data = (0..SIZE).map(pattern).collect::<Vec<_>>();
+                    writer.write_all(&data).await.unwrap();
+                });
+                let mut buf = vec![0; SIZE];
+                stream.read_exact(&mut buf).await.unwrap();
+                write.await;
+                buf
+            });
+
+            let client = connect_client_to(backend_addr, ChannelConfig::default()).await;
+            let mut writer = client.clone();
+            let write = async_std::task::spawn(async move {
+                let data = (0..SIZE).map(pattern).collect::<Vec<_>>();
+                for chunk in data.chunks(60_000) {
+                    writer.write_all(&masked_frame(0x82, chunk)).await.unwrap();
+                }
+            });
+            let read = async_std::task::spawn(async move {
+                let mut reader = client;
+                let mut received = Vec::new();
+                while received.len() < SIZE {
+                    let mut header = [0; 2];
+                    reader.read_exact(&mut header).await.unwrap();
+                    assert_eq!(header[0], 0x82);
+                    let len = match header[1] {
+                        126 => {
+                            let mut len = [0; 2];
+                            reader.read_exact(&mut len).await.unwrap();
+                            u16::from_be_bytes(len) as usize
+                        }
+                        127 => {
+                            let mut len = [0; 8];
+                            reader.read_exact(&mut len).await.unwrap();
+                            u64::from_be_bytes(len) as usize
+                        }
+                        n => n as usize,
+                    };
+                    let start = received.len();
+                    received.resize(start + len, 0);
+                    reader.read_exact(&mut received[start..]).await.unwrap();
+                }
+                (received, reader)
+            });
+
+            // Keeps the client stream open until the backend has received everything
+            let relay = async {
+                write.await;
+                let (to_client, _client) = read.await;
+                (to_client, backend.await)
+            };
+            let (to_client, to_backend) =
+                async_std::future::timeout(Duration::from_secs(30), relay)
+                    .await
+                    .expect("relay did not complete");
+            assert!(to_client.iter().enumerate().all(|(i, &b)| b == pattern(i)));
+            assert!(to_backend.iter().enumerate().all(|(i, &b)| b == pattern(i)));
+        });
+    }
+
+    #[test]
+    fn time_to_first_byte_budget_works() {
+        async_std::task::block_on(async {
+            let config = ChannelConfig {
+                time_to_first_byte_budget: Some(Duration::from_millis(50)),
+                ..Default::default()
+            };
+            let mut client = connect_client(config).await;
+            let reason = b"time-to-first-byte budget exceeded";
+            let mut reply = vec![0; 4 + reason.len()];
+            client.read_exact(&mut reply).await.unwrap();
+
+            assert_eq!(reply[..4], [0x88, 2 + reason.len() as u8, 0x03, 0xF0]);
+            assert_eq!(&reply[4..], reason);
+        });
+    }
+
+    #[test]
+    fn close_payload_is_echoed() {
+        async_std::task::block_on(async {
+            let mut client = connect_client(ChannelConfig::default()).await;
+            client.write_all(&masked_frame(0x88, &[])).await.unwrap();
+            let mut reply = Vec::new();
+            client.read_to_end(&mut reply).await.unwrap();
+            assert_eq!(reply, [0x88, 0x00]);
+
+            let mut client = connect_client(ChannelConfig::default()).await;
+            client
+                .write_all(&masked_frame(0x88, &[0x03, 0xE9]))
+                .await
+                .unwrap();
+            let mut reply = Vec::new();
+            client.read_to_end(&mut reply).await.unwrap();
+            assert_eq!(reply, [0x88, 0x02, 0x03, 0xE9]);
+
+            let mut client = connect_client(ChannelConfig::default()).await;
+            client
+                .write_all(&masked_frame(0x88, b"\x03\xE9bye"))
+                .await
+                .unwrap();
+            let mut reply = Vec::new();
+            client.read_to_end(&mut reply).await.unwrap();
+            assert_eq!(reply, *b"\x88\x05\x03\xE9bye");
+        });
+    }
+
+    #[test]
+    fn access_log_line_works() {
+        async_std::task::block_on(async {
+            let (_client, mut channel) =
+                new_channel("127.0.0.1:1".parse().unwrap(), ChannelConfig::default()).await;
+            channel.request_line = Some("GET /chat HTTP/1.1".to_owned());
+            channel.response_status = Some(101);
+            let line = channel.access_log_line();
+
+            let (host, rest) = line.split_once(" - - [").unwrap();
+            assert_eq!(host, "127.0.0.1");
+            let (date, rest) = rest.split_once("] ").unwrap();
+            assert!(date.ends_with(" +0000"));
+            assert_eq!(rest, "\"GET /chat HTTP/1.1\" 101 0");
+        });
+    }
+
+    #[test]
+    fn handshake_rejection_works() {
+        async_std::task::block_on(async {
+            let config = ChannelConfig {
+                deadline_header: Some("X-Deadline".to_owned()),
+                ..ChannelConfig::default()
+            };
+            let (_client, mut channel) = new_channel("127.0.0.1:1".parse().unwrap(), config).await;
+            let mut check = |request: &str| {
+                let request = RequestDecoder::<NoBodyDecoder>::default()
+                    .decode_from_bytes(request.as_bytes())
+                    .unwrap();
+                channel.handle_handshake_request(&request).err()
+            };
+
+            let headers = "Upgrade: websocket\r\nConnection: Upgrade\r\n\
+                           Sec-WebSocket-Version: 13\r\nSec-WebSocket-Key: foo\r\n";
+            assert_eq!(check(&format!("GET / HTTP/1.1\r\n{}\r\n", headers)), None);
+            assert_eq!(
+                check(&format!("POST / HTTP/1.1\r\n{}\r\n", headers)),
+                Some(HandshakeRejection::BadMethod)
+            );
+            assert_eq!(
+                check(&format!("GET / HTTP/1.0\r\n{}\r\n", headers)),
+                Some(HandshakeRejection::BadVersion)
+            );
+            assert_eq!(
+                check("GET / HTTP/1.1\r\nUpgrade: h2c\r\n\r\n"),
+                Some(HandshakeRejection::BadUpgrade)
+            );
+            assert_eq!(
+                check("GET / HTTP/1.1\r\nConnection: close\r\n\r\n"),
+                Some(HandshakeRejection::BadConnection)
+            );
+            assert_eq!(
+                check("GET / HTTP/1.1\r\nSec-WebSocket-Version: 8\r\n\r\n"),
+                Some(HandshakeRejection::BadWebSocketVersion)
+            );
+            assert_eq!(
+                check("GET / HTTP/1.1\r\nUpgrade: websocket\r\n\r\n"),
+                Some(HandshakeRejection::MissingKey)
+            );
+            assert_eq!(
+                check("GET / HTTP/1.1\r\nHost: example.com\r\n\r\n"),
+                Some(HandshakeRejection::UpgradeRequired)
+            );
+            assert_eq!(
+                check(&format!(
+                    "GET / HTTP/1.1\r\n{}X-Deadline: soon\r\n\r\n",
+                    headers
+                )),
+                Some(HandshakeRejection::BadDeadline)
+            );
+            assert_eq!(
+                check(&format!(
+                    "GET / HTTP/1.1\r\n{}X-Deadline: {}\r\n\r\n",
+                    headers,
+                    u64::MAX
+                )),
+                Some(HandshakeRejection::BadDeadline)
+            );
+        });
+    }
+
+    #[test]
+    fn incomplete_close_is_detected() {
+        async_std::task::block_on(async {
+            let (_client, mut channel) =
+                new_channel("127.0.0.1:1".parse().unwrap(), ChannelConfig::default()).await;
+            assert!(!channel.is_close_incomplete());
+
+            channel.handshake = Handshake::Done;
+            assert!(channel.is_close_incomplete());
+
+            channel.closing = Closing::Closed;
+            assert!(!channel.is_close_incomplete());
+        });
+    }
+
+    #[test]
+    fn incomplete_close_is_warned_on_drop() {
+        capture_logs();
+        async_std::task::block_on(async {
+            let backend = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let backend_addr = backend.local_addr().unwrap();
+            async_std::task::spawn(async move {
+                let (mut stream, _) = backend.accept().await.unwrap();
+                let _ = stream.read_to_end(&mut Vec::new()).await;
+            });
+
+            let (mut client, mut channel) =
+                new_channel(backend_addr, ChannelConfig::default()).await;
+            client.write_all(HANDSHAKE_REQUEST).await.unwrap();
+            let result = async_std::future::timeout(Duration::from_millis(200), &mut channel).await;
+            assert!(result.is_err());
+            let response = read_response(&mut client).await;
+            assert!(response.starts_with(b"HTTP/1.1 101 "));
+
+            // Dropped in the middle of the session
+            drop(channel);
+            let warned = captured_logs(log::Level::Warn).iter().any(|w| {
+                w.starts_with("A proxy channel was dropped before the closing handshake")
+                    && w.contains("conn_id=0,")
+            });
+            assert!(warned);
+        });
+    }
+
+    #[test]
+    fn write_coalescing_works() {
+        async_std::task::block_on(async {
+            let backend = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let backend_addr = backend.local_addr().unwrap();
+            async_std::task::spawn(async move {
+                let (mut stream, _) = backend.accept().await.unwrap();
+                for b in b"abc" {
+                    stream.write_all(&[*b]).await.unwrap();
+                    async_std::task::sleep(Duration::from_millis(10)).await;
+                }
+                let _ = stream.read(&mut [0; 1]).await;
+            });
+
+            let config = ChannelConfig {
+                write_coalescing_window: Some(Duration::from_millis(200)),
+                ..Default::default()
+            };
+            let mut client = spawn_channel(backend_addr, config).await;
+            client.write_all(HANDSHAKE_REQUEST).await.unwrap();
+            read_response(&mut client).await;
+
+            let mut buf = [0; 16];
+            let size = client.read(&mut buf).await.unwrap();
+            assert_eq!(&buf[..size], b"\x82\x01a\x82\x01b\x82\x01c");
+        });
+    }
+
+    #[test]
+    fn backend_banner_follows_handshake_response() {
+        async_std::task::block_on(async {
+            let backend = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let backend_addr = backend.local_addr().unwrap();
+            async_std::task::spawn(async move {
+                let (mut stream, _) = backend.accept().await.unwrap();
+                stream.write_all(b"hello").await.unwrap();
+                let _ = stream.read(&mut [0; 1]).await;
+            });
+
+            let mut client = spawn_channel(backend_addr, ChannelConfig::default()).await;
+            client.write_all(HANDSHAKE_REQUEST).await.unwrap();
+            let response = read_response(&mut client).await;
+            assert!(response.starts_with(b"HTTP/1.1 101 "));
+
+            let mut frame = [0; 7];
+            client.read_exact(&mut frame).await.unwrap();
+            assert_eq!(frame, [0x82, 5, b'h', b'e', b'l', b'l', b'o']);
+        });
+    }
+
+    #[test]
+    fn backend_banner_is_sent_within_the_handshake_poll() {
+        async_std::task::block_on(async {
+            let backend = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let backend_addr = backend.local_addr().unwrap();
+            async_std::task::spawn(async move {
+                let (mut stream, _) = backend.accept().await.unwrap();
+                stream.write_all(b"hello").await.unwrap();
+                let _ = stream.read(&mut [0; 1]).await;
+            });
+
+            let (mut client, mut channel) =
+                new_channel(backend_addr, ChannelConfig::default()).await;
+            client.write_all(HANDSHAKE_REQUEST).await.unwrap();
+
+            // The time to the first frame is bounded by the poll that completes the handshake,
+            // so the channel is not polled again once the handshake is done
+            let mut cx = Context::from_waker(Waker::noop());
+            let started_at = Instant::now();
+            while !channel.handshake.done() {
+                assert!(Pin::new(&mut channel).poll(&mut cx).is_pending());
+                async_std::task::sleep(Duration::from_millis(20)).await;
+            }
+            let read_frame = async {
+                let response = read_response(&mut client).await;
+                assert!(response.starts_with(b"HTTP/1.1 101 "));
+                let mut frame = [0; 7];
+                client.read_exact(&mut frame).await.unwrap();
+                frame
+            };
+            let frame = async_std::future::timeout(Duration::from_secs(1), read_frame)
+                .await
+                .unwrap();
+            assert_eq!(frame, [0x82, 5, b'h', b'e', b'l', b'l', b'o']);
+            assert!(started_at.elapsed() < Duration::from_secs(1));
+        });
+    }
+
+    #[test]
+    fn server_and_date_headers() {
+        async_std::task::block_on(async {
+            let backend = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let config = ChannelConfig {
+                server_header: Some("wstcp test".to_owned()),
+                emit_date: true,
+                ..Default::default()
+            };
+            let mut client = spawn_channel(backend.local_addr().unwrap(), config).await;
+            client.write_all(HANDSHAKE_REQUEST).await.unwrap();
+            let response = String::from_utf8(read_response(&mut client).await).unwrap();
+            assert!(response.starts_with("HTTP/1.1 101 "));
+            assert!(response.contains("\r\nServer: wstcp test\r\n"));
+
+            let date = response
+                .split("\r\n")
+                .find_map(|line| line.strip_prefix("Date: "))
+                .unwrap();
+            assert_eq!(date.len(), "Sun, 06 Nov 1994 08:49:37 GMT".len());
+            assert!(date.ends_with(" GMT"));
+        });
+    }
+
+    #[test]
+    fn observer_works() {
+        #[derive(Default)]
+        struct Counter {
+            opens: AtomicUsize,
+            closes: AtomicUsize,
+            last_code: AtomicUsize,
+        }
+        impl ChannelObserver for Counter {
+            fn on_handshake(&self, request: &Request<()>) -> Decision {
+                if request.request_target().as_str() == "/private" {
+                    Decision::Reject(401)
+                } else {
+                    Decision::Allow
+                }
+            }
+
+            fn on_open(&self, peer: Option<SocketAddr>) {
+                assert!(peer.is_some());
+                self.opens.fetch_add(1, Ordering::SeqCst);
+            }
+
+            fn on_close(&self, code: u16) {
+                self.last_code.store(usize::from(code), Ordering::SeqCst);
+                self.closes.fetch_add(1, Ordering::SeqCst);
+            }
+        }
+
+        async_std::task::block_on(async {
+            let counter = Arc::new(Counter::default());
+            let config = ChannelConfig {
+                observer: Some(counter.clone()),
+                ..Default::default()
+            };
+
+            // A full lifecycle ending with a closing handshake started by the client
+            let mut client = connect_client(config.clone()).await;
+            assert_eq!(counter.opens.load(Ordering::SeqCst), 1);
+            client
+                .write_all(&masked_frame(0x88, &1000u16.to_be_bytes()))
+                .await
+                .unwrap();
+            let mut frames = Vec::new();
+            client.read_to_end(&mut frames).await.unwrap();
+            assert_eq!(frames, [0x88, 0x02, 0x03, 0xE8]);
+            for _ in 0..100 {
+                if counter.closes.load(Ordering::SeqCst) > 0 {
+                    break;
+                }
+                async_std::task::sleep(Duration::from_millis(10)).await;
+            }
+            assert_eq!(counter.closes.load(Ordering::SeqCst), 1);
+            assert_eq!(counter.last_code.load(Ordering::SeqCst), 1000);
+
+            // Rejected handshakes are neither opened nor closed
+            let mut client = spawn_channel("127.0.0.1:1".parse().unwrap(), config).await;
+            let request = String::from_utf8(HANDSHAKE_REQUEST.to_vec())
+                .unwrap()
+                .replacen("GET / ", "GET /private ", 1);
+            client.write_all(request.as_bytes()).await.unwrap();
+            let response = read_response(&mut client).await;
+            assert!(response.starts_with(b"HTTP/1.1 401 Unauthorized\r\n"));
+            client.read_to_end(&mut Vec::new()).await.unwrap();
+            assert_eq!(counter.opens.load(Ordering::SeqCst), 1);
+            assert_eq!(counter.closes.load(Ordering::SeqCst), 1);
+        });
+    }
+
+    #[test]
+    fn response_header_hook_works() {
+        async_std::task::block_on(async {
+            let backend = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let hook = ResponseHeaderHook::new(|request| {
+                let mut headers = request
+                    .header()
+                    .get_field("X-Request-Id")
+                    .map(|id| ("X-Request-Id".to_owned(), id.to_owned()))
+                    .into_iter()
+                    .collect::<Vec<_>>();
+                headers.push(("Bad Name".to_owned(), "skipped".to_owned()));
+                headers
+            });
+            let config = ChannelConfig {
+                response_header_hook: Some(hook),
+                ..Default::default()
+            };
+            let mut client = spawn_channel(backend.local_addr().unwrap(), config).await;
+            let request = String::from_utf8(HANDSHAKE_REQUEST.to_vec())
+                .unwrap()
+                .replacen("Host:", "X-Request-Id: abc-123\r\nHost:", 1);
+            client.write_all(request.as_bytes()).await.unwrap();
+            let response = String::from_utf8(read_response(&mut client).await).unwrap();
+            assert!(response.starts_with("HTTP/1.1 101 "));
+            assert!(response.contains("\r\nX-Request-Id: abc-123\r\n"));
+            assert!(!response.contains("skipped"));
+        });
+    }
+
+    #[test]
+    fn response_header_hook_is_not_called_for_rejected_requests() {
+        async_std::task::block_on(async {
+            let calls = Arc::new(AtomicUsize::new(0));
+            let hook_calls = calls.clone();
+            let hook = ResponseHeaderHook::new(move |_| {
+                hook_calls.fetch_add(1, Ordering::SeqCst);
+                Vec::new()
+            });
+            let config = ChannelConfig {
+                response_header_hook: Some(hook),
+                ..Default::default()
+            };
+
+            // Invalid request
+            let mut client = spawn_channel("127.0.0.1:1".parse().unwrap(), config.clone()).await;
+            let request = String::from_utf8(HANDSHAKE_REQUEST.to_vec())
+                .unwrap()
+                .replacen("HTTP/1.1", "HTTP/1.0", 1);
+            client.write_all(request.as_bytes()).await.unwrap();
+            let response = read_response(&mut client).await;
+            assert!(response.starts_with(b"HTTP/1.1 400 "));
+
+            // Unreachable real server
+            let mut client = spawn_channel("127.0.0.1:1".parse().unwrap(), config).await;
+            client.write_all(HANDSHAKE_REQUEST).await.unwrap();
+            let response = read_response(&mut client).await;
+            assert!(response.starts_with(b"HTTP/1.1 503 "));
+            assert_eq!(calls.load(Ordering::SeqCst), 0);
+        });
+    }
+
+    #[test]
+    fn extra_response_headers_work() {
+        async_std::task::block_on(async {
+            let config = ChannelConfig {
+                extra_response_headers: vec![("X-Proxy-Version".to_owned(), "1.2.3".to_owned())],
+                ..Default::default()
+            };
+            let mut client = spawn_channel("127.0.0.1:1".parse().unwrap(), config.clone()).await;
+            client.write_all(HANDSHAKE_REQUEST).await.unwrap();
+            let response = String::from_utf8(read_response(&mut client).await).unwrap();
+            assert!(!response.contains("X-Proxy-Version"));
+
+            let backend = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let mut client = spawn_channel(backend.local_addr().unwrap(), config).await;
+            client.write_all(HANDSHAKE_REQUEST).await.unwrap();
+            let response = String::from_utf8(read_response(&mut client).await).unwrap();
+            assert!(response.starts_with("HTTP/1.1 101 "));
+            assert!(response.contains("\r\nX-Proxy-Version: 1.2.3\r\n"));
+        });
+    }
+
+    #[test]
+    fn larger_buffers_relay_bursts_in_fewer_writes() {
+        const SIZE: usize = 256 * 1024;
+        async fn relay_burst(config: ChannelConfig) -> usize {
+            let backend = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let backend_addr = backend.local_addr().unwrap();
+            async_std::task::spawn(async move {
+                let (mut stream, _) = backend.accept().await.unwrap();
+                stream.write_all(&[0; SIZE]).await.unwrap();
+                let _ = stream.read_to_end(&mut Vec::new()).await;
+            });
+
+            let mut client = connect_client_to(backend_addr, config).await;
+            let (mut received, mut frames) = (0, 0);
+            while received < SIZE {
+                let mut header = [0; 2];
+                client.read_exact(&mut header).await.unwrap();
+                let len = match header[1] {
+                    126 => {
+                        let mut len = [0; 2];
+                        client.read_exact(&mut len).await.unwrap();
+                        u16::from_be_bytes(len) as usize
+                    }
+                    127 => {
+                        let mut len = [0; 8];
+                        client.read_exact(&mut len).await.unwrap();
+                        u64::from_be_bytes(len) as usize
+                    }
+                    n => n as usize,
+                };
+                let mut payload = vec![0; len];
+                client.read_exact(&mut payload).await.unwrap();
+                received += len;
+                frames += 1;
+            }
+            frames
+        }
+
+        async_std::task::block_on(async {
+            // Each frame is written after a single read from the real server
+            let small = relay_burst(ChannelConfig::default()).await;
+            let large = relay_burst(ChannelConfig {
+                buffer_size: Some(64 * 1024),
+                ..Default::default()
+            })
+            .await;
+            assert!(small >= SIZE / DEFAULT_BUF_SIZE);
+            assert!(large < small);
+        });
+    }
+}
@@ -0,0 +1,115 @@
+use crate::{Error, ErrorKind, Result};
+use std::fmt;
+use std::net::IpAddr;
+use std::str::FromStr;
+use trackable::error::ErrorKindExt;
+
+/// Range of IP addresses in CIDR notation (e.g., `10.0.0.0/8` or `2001:db8::/32`).
+///
+/// An address without a prefix length (e.g., `192.0.2.1`) denotes the address itself.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IpCidr {
+    addr: IpAddr,
+    prefix_len: u8,
+}
+impl IpCidr {
+    /// Returns `true` if `addr` is in the range.
+    ///
+    /// IPv4-mapped IPv6 addresses are matched as IPv4 addresses.
+    pub fn contains(&self, addr: IpAddr) -> bool {
+        let addr = match addr {
+            IpAddr::V6(a) => a.to_ipv4_mapped().map_or(addr, IpAddr::V4),
+            IpAddr::V4(_) => addr,
+        };
+        match (self.addr, addr) {
+            (IpAddr::V4(net), IpAddr::V4(a)) => {
+                let mask = u32::MAX
+                    .checked_shl(32 - u32::from(self.prefix_len))
+                    .unwrap_or(0);
+                u32::from(net) & mask == u32::from(a) & mask
+            }
+            (IpAddr::V6(net), IpAddr::V6(a)) => {
+                let mask = u128::MAX
+                    .checked_shl(128 - u32::from(self.prefix_len))
+                    .unwrap_or(0);
+                u128::from(net) & mask == u128::from(a) & mask
+            }
+            _ => false,
+        }
+    }
+}
+impl FromStr for IpCidr {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (addr, prefix_len) = match s.split_once('/') {
+            Some((addr, len)) => (addr, Some(len)),
+            None => (s, None),
+        };
+        let addr: IpAddr = track!(addr.parse().map_err(|e| ErrorKind::InvalidInput.cause(e)); s)?;
+        let max_len = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix_len = match prefix_len {
+            Some(len) => {
+                track!(len.parse::<u8>().map_err(|e| ErrorKind::InvalidInput.cause(e)); s)?
+            }
+            None => max_len,
+        };
+        track_assert!(prefix_len <= max_len, ErrorKind::InvalidInput; s);
+        Ok(IpCidr { addr, prefix_len })
+    }
+}
+impl fmt::Display for IpCidr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix_len)
+    }
+}
+
+/// Returns `true` if a client at `addr` may connect.
+///
+/// The deny-list takes precedence: an address in `deny` is refused even if it is also in `allow`.
+/// An empty `allow` allows every address that is not denied.
+pub fn is_allowed(addr: IpAddr, allow: &[IpCidr], deny: &[IpCidr]) -> bool {
+    if deny.iter().any(|c| c.contains(addr)) {
+        return false;
+    }
+    allow.is_empty() || allow.iter().any(|c| c.contains(addr))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn cidr(s: &str) -> IpCidr {
+        s.parse().unwrap()
+    }
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn cidr_works() {
+        assert!(cidr("10.0.0.0/8").contains(ip("10.1.2.3")));
+        assert!(!cidr("10.0.0.0/8").contains(ip("11.0.0.1")));
+        assert!(cidr("192.0.2.1").contains(ip("192.0.2.1")));
+        assert!(!cidr("192.0.2.1").contains(ip("192.0.2.2")));
+        assert!(cidr("0.0.0.0/0").contains(ip("203.0.113.9")));
+        assert!(cidr("2001:db8::/32").contains(ip("2001:db8::1")));
+        assert!(!cidr("2001:db8::/32").contains(ip("10.0.0.1")));
+        assert!(cidr("127.0.0.0/8").contains(ip("::ffff:127.0.0.1")));
+
+        assert!("10.0.0.0/33".parse::<IpCidr>().is_err());
+        assert!("10.0.0/8".parse::<IpCidr>().is_err());
+        assert!("10.0.0.0/x".parse::<IpCidr>().is_err());
+    }
+
+    #[test]
+    fn deny_takes_precedence() {
+        let allow = [cidr("10.0.0.0/8")];
+        let deny = [cidr("10.0.0.0/16")];
+        assert!(is_allowed(ip("10.1.0.1"), &allow, &deny));
+        assert!(!is_allowed(ip("10.0.0.1"), &allow, &deny));
+        assert!(!is_allowed(ip("192.0.2.1"), &allow, &deny));
+        assert!(is_allowed(ip("192.0.2.1"), &[], &deny));
+    }
+}
@@ -5,21 +5,41 @@ use trackable::error::{ErrorKind as TrackableErrorKind, ErrorKindExt, TrackableE
 pub struct Error(TrackableError<ErrorKind>);
 impl From<std::io::Error> for Error {
     fn from(f: std::io::Error) -> Self {
-        ErrorKind::Other.cause(f).into()
+        ErrorKind::Io.cause(f).into()
     }
 }
 impl From<bytecodec::Error> for Error {
     fn from(f: bytecodec::Error) -> Self {
-        // TODO
-        ErrorKind::Other.takes_over(f).into()
+        let kind = match *f.kind() {
+            bytecodec::ErrorKind::InvalidInput | bytecodec::ErrorKind::UnexpectedEos => {
+                ErrorKind::ProtocolViolation
+            }
+            _ if f.concrete_cause::<std::io::Error>().is_some() => ErrorKind::Io,
+            _ => ErrorKind::Other,
+        };
+        kind.takes_over(f).into()
     }
 }
 
 /// Possible error kinds.
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
-#[allow(missing_docs)]
 pub enum ErrorKind {
+    /// Invalid argument or configuration.
     InvalidInput,
+
+    /// The real server cannot be connected.
+    BackendUnavailable,
+
+    /// The WebSocket handshake request was malformed or rejected.
+    HandshakeFailed,
+
+    /// A peer violated a protocol (e.g., sent a malformed WebSocket frame).
+    ProtocolViolation,
+
+    /// An I/O error other than the above.
+    Io,
+
+    /// Any other error.
     Other,
 }
 impl TrackableErrorKind for ErrorKind {}
@@ -1,4 +1,30 @@
-use crate::opcode::Opcode;
+//! WebSocket framing layer ([RFC 6455, section 5]).
+//!
+//! `FrameDecoder` decodes the frames sent by clients and `FrameEncoder` encodes the frames sent
+//! by servers (or by clients, with `FrameEncoder::set_mask_outgoing`).
+//! The payloads of data frames are streamed rather than held in `Frame`.
+//!
+//! # Examples
+//!
+//! ```
+//! use bytecodec::{Decode, Encode, Eos};
+//! use wstcp::frame::{Frame, FrameDecoder, FrameEncoder};
+//!
+//! // Frames sent by clients are masked
+//! let mut encoder = FrameEncoder::default();
+//! encoder.set_mask_outgoing(true);
+//! encoder.start_encoding(Frame::Ping { data: b"hi".to_vec() })?;
+//! let mut buf = [0; 16];
+//! let size = encoder.encode(&mut buf, Eos::new(false))?;
+//!
+//! let mut decoder = FrameDecoder::default();
+//! decoder.decode(&buf[..size], Eos::new(false))?;
+//! assert!(matches!(decoder.finish_decoding()?, Frame::Ping { data } if data == b"hi"));
+//! # Ok::<(), bytecodec::Error>(())
+//! ```
+//!
+//! [RFC 6455, section 5]: https://tools.ietf.org/html/rfc6455#section-5
+use crate::validator::{FrameValidator, Utf8Validator, MAX_CONTROL_PAYLOAD_LEN};
 use crate::{Error, Result};
 use bytecodec::bytes::{BytesEncoder, CopyableBytesDecoder};
 use bytecodec::combinator::Slice;
@@ -6,24 +32,56 @@ use bytecodec::io::StreamState;
 use bytecodec::{ByteCount, Decode, Encode, Eos};
 use byteorder::{BigEndian, ByteOrder};
 use std::cmp;
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
 use std::io::{self, Read, Write};
 
 const FIN_FLAG: u8 = 0b1000_0000;
 const MASK_FLAG: u8 = 0b1000_0000;
 
+pub use crate::opcode::Opcode;
+pub use crate::validator::Violation;
+
 const BUF_SIZE: usize = 4096;
 
+// Large enough for the payload of any control frame (RFC 6455, 5.5)
+const MIN_BUF_SIZE: usize = MAX_CONTROL_PAYLOAD_LEN as usize;
+
+/// WebSocket frame.
 #[derive(Debug)]
 pub enum Frame {
-    ConnectionClose { code: u16, reason: Vec<u8> },
-    Ping { data: Vec<u8> },
-    Pong { data: Vec<u8> },
+    /// Close frame.
+    ConnectionClose {
+        /// Status code, or `None` if the frame has no payload.
+        code: Option<u16>,
+
+        /// Reason (UTF-8 text) following the status code.
+        reason: Vec<u8>,
+    },
+
+    /// Ping frame.
+    Ping {
+        /// Application data, to be echoed by the Pong frame.
+        data: Vec<u8>,
+    },
+
+    /// Pong frame.
+    Pong {
+        /// Application data of the Ping frame answered.
+        data: Vec<u8>,
+    },
+
+    /// Text, binary or continuation frame.
+    ///
+    /// Its payload is not held here but streamed: `FrameDecoder::write_decoded_data` takes it
+    /// out while the frame is being decoded, and `FrameEncoder::start_encoding_data` feeds it.
     Data,
 }
 
 #[derive(Debug, Clone)]
 struct FrameHeader {
-    _fin_flag: bool,
+    fin: bool,
+    rsv: u8,
     opcode: Opcode,
     mask: Option<[u8; 4]>,
     payload_len: u64,
@@ -31,7 +89,8 @@ struct FrameHeader {
 impl FrameHeader {
     fn from_bytes(b: [u8; 2]) -> bytecodec::Result<Self> {
         let mut header = FrameHeader {
-            _fin_flag: (b[0] & FIN_FLAG) != 0,
+            fin: (b[0] & FIN_FLAG) != 0,
+            rsv: (b[0] >> 4) & 0b111,
             opcode: track!(Opcode::from_u8(b[0] & 0b1111))?,
             mask: None,
             payload_len: u64::from(b[1] & 0b0111_1111),
@@ -45,20 +104,73 @@ impl FrameHeader {
     }
 }
 
+/// Encoder of WebSocket frames.
+///
+/// Control frames are started by `Encode::start_encoding` and data frames by
+/// `start_encoding_data` or `start_encoding_message`. Every frame has the FIN bit set.
 #[derive(Debug)]
 pub struct FrameEncoder {
-    header: Slice<BytesEncoder<[u8; 2 + 8]>>,
+    header: Slice<BytesEncoder<[u8; 2 + 8 + 4]>>,
     payload: Vec<u8>,
     payload_offset: usize,
     payload_length: usize,
+    data_bytes: u64,
+    max_data_size: usize,
+    mask_outgoing: bool,
+    mask: Option<[u8; 4]>,
 }
 impl FrameEncoder {
-    pub fn start_encoding_data<R: Read>(&mut self, mut reader: R) -> Result<StreamState> {
+    /// Makes a new `FrameEncoder` instance whose payload buffer has `size` bytes.
+    ///
+    /// The size is raised to 125 bytes (the maximum payload size of control frames) if smaller.
+    /// The default size is 4096 bytes.
+    pub fn with_buffer_size(size: usize) -> Self {
+        FrameEncoder {
+            header: Default::default(),
+            payload: vec![0; cmp::max(size, MIN_BUF_SIZE)],
+            payload_length: 0,
+            payload_offset: 0,
+            data_bytes: 0,
+            max_data_size: usize::MAX,
+            mask_outgoing: false,
+            mask: None,
+        }
+    }
+
+    /// Limits the payload size of the frames made by `start_encoding_data`.
+    ///
+    /// The payload size is also bounded by the size of the internal buffer.
+    pub fn set_max_data_size(&mut self, size: usize) {
+        self.max_data_size = cmp::max(size, 1);
+    }
+
+    /// Makes the encoder mask the frames with a random key.
+    ///
+    /// RFC 6455 forbids servers from masking frames, so this is only useful for diagnosing
+    /// client bugs.
+    pub fn set_mask_outgoing(&mut self, enabled: bool) {
+        self.mask_outgoing = enabled;
+    }
+
+    /// Returns the total number of bytes read by `start_encoding_data`.
+    pub fn data_bytes(&self) -> u64 {
+        self.data_bytes
+    }
+
+    /// Starts encoding a data frame whose payload is read (once) from `reader`.
+    ///
+    /// Nothing is done if the encoder is not idle. The returned state is that of `reader`.
+    pub fn start_encoding_data<R: Read>(
+        &mut self,
+        mut reader: R,
+        opcode: Opcode,
+    ) -> Result<StreamState> {
         if !self.is_idle() {
             return Ok(StreamState::Normal);
         }
 
-        match reader.read(&mut self.payload) {
+        let limit = cmp::min(self.payload.len(), self.max_data_size);
+        match reader.read(&mut self.payload[..limit]) {
             Err(e) => {
                 if e.kind() == io::ErrorKind::WouldBlock {
                     return Ok(StreamState::WouldBlock);
@@ -68,19 +180,38 @@ impl FrameEncoder {
             }
             Ok(0) => return Ok(StreamState::Eos),
             Ok(size) => {
-                track!(self.start_encoding_header(Opcode::BinaryFrame, size))?;
+                track!(self.start_encoding_header(opcode, size))?;
+                self.data_bytes += size as u64;
             }
         }
         Ok(StreamState::Normal)
     }
 
+    /// Starts encoding a data frame made by the encoder user (e.g., a heartbeat message).
+    ///
+    /// Unlike `start_encoding_data`, the payload is not counted in `data_bytes`.
+    pub fn start_encoding_message(
+        &mut self,
+        opcode: Opcode,
+        payload: &[u8],
+    ) -> bytecodec::Result<()> {
+        track_assert!(self.is_idle(), bytecodec::ErrorKind::EncoderFull);
+        track_assert!(
+            payload.len() <= self.payload.len(),
+            bytecodec::ErrorKind::InvalidInput
+        );
+        track!(self.start_encoding_header(opcode, payload.len()))?;
+        self.payload[..payload.len()].copy_from_slice(payload);
+        Ok(())
+    }
+
     fn start_encoding_header(
         &mut self,
         opcode: Opcode,
         payload_len: usize,
     ) -> bytecodec::Result<()> {
-        let header_size;
-        let mut header = [0; 2 + 8];
+        let mut header_size;
+        let mut header = [0; 2 + 8 + 4];
         header[0] = FIN_FLAG | (opcode as u8);
         if payload_len < 126 {
             header[1] = payload_len as u8;
@@ -95,8 +226,17 @@ impl FrameEncoder {
             header_size = 10;
         };
 
+        self.mask = None;
+        if self.mask_outgoing {
+            let mask = random_mask();
+            header[1] |= MASK_FLAG;
+            header[header_size..][..4].copy_from_slice(&mask);
+            header_size += 4;
+            self.mask = Some(mask);
+        }
+
         track!(self.header.start_encoding(header))?;
-        self.header.set_consumable_bytes(header_size);
+        self.header.set_consumable_bytes(header_size as u64);
         self.payload_length = payload_len;
         Ok(())
     }
@@ -118,6 +258,11 @@ impl Encode for FrameEncoder {
             self.payload_length - self.payload_offset,
         );
         buf[offset..][..size].copy_from_slice(&self.payload[self.payload_offset..][..size]);
+        if let Some(mask) = self.mask {
+            for (i, b) in buf[offset..][..size].iter_mut().enumerate() {
+                *b ^= mask[(self.payload_offset + i) % 4];
+            }
+        }
         self.payload_offset += size;
         if self.payload_offset == self.payload_length {
             self.payload_length = 0;
@@ -130,7 +275,14 @@ impl Encode for FrameEncoder {
     fn start_encoding(&mut self, item: Self::Item) -> bytecodec::Result<()> {
         track_assert!(self.is_idle(), bytecodec::ErrorKind::EncoderFull);
         match item {
-            Frame::ConnectionClose { code, reason } => {
+            Frame::ConnectionClose { code: None, reason } => {
+                track_assert!(reason.is_empty(), bytecodec::ErrorKind::InvalidInput);
+                track!(self.start_encoding_header(Opcode::ConnectionClose, 0))?;
+            }
+            Frame::ConnectionClose {
+                code: Some(code),
+                reason,
+            } => {
                 track!(self.start_encoding_header(Opcode::ConnectionClose, 2 + reason.len()))?;
                 self.payload_length = 2 + reason.len();
                 track_assert!(
@@ -140,16 +292,23 @@ impl Encode for FrameEncoder {
                 BigEndian::write_u16(&mut self.payload, code);
                 self.payload[2..][..reason.len()].copy_from_slice(&reason);
             }
+            Frame::Ping { data } | Frame::Pong { data } if data.len() > self.payload.len() => {
+                track_panic!(bytecodec::ErrorKind::InvalidInput; data.len());
+            }
+            Frame::Ping { data } => {
+                track!(self.start_encoding_header(Opcode::Ping, data.len()))?;
+                self.payload_length = data.len();
+                self.payload[..data.len()].copy_from_slice(&data);
+            }
             Frame::Pong { data } => {
                 track!(self.start_encoding_header(Opcode::Pong, data.len()))?;
                 self.payload_length = data.len();
-                track_assert!(
-                    self.payload_length <= self.payload.len(),
-                    bytecodec::ErrorKind::InvalidInput
-                );
                 self.payload[..data.len()].copy_from_slice(&data);
             }
-            Frame::Ping { .. } | Frame::Data => unreachable!(),
+            Frame::Data => track_panic!(
+                bytecodec::ErrorKind::InvalidInput,
+                "Data frames are encoded by `start_encoding_data`"
+            ),
         }
         Ok(())
     }
@@ -165,15 +324,17 @@ impl Encode for FrameEncoder {
 }
 impl Default for FrameEncoder {
     fn default() -> Self {
-        FrameEncoder {
-            header: Default::default(),
-            payload: vec![0; 4096],
-            payload_length: 0,
-            payload_offset: 0,
-        }
+        FrameEncoder::with_buffer_size(BUF_SIZE)
     }
 }
 
+fn random_mask() -> [u8; 4] {
+    // Each `RandomState` is seeded differently, which is random enough for a debugging aid
+    let mut hasher = RandomState::new().build_hasher();
+    hasher.write_u8(0);
+    (hasher.finish() as u32).to_be_bytes()
+}
+
 #[derive(Debug, Default)]
 struct FrameHeaderDecoder {
     fixed_bytes: CopyableBytesDecoder<[u8; 2]>,
@@ -222,6 +383,12 @@ impl Decode for FrameHeaderDecoder {
             }
             127 => {
                 header.payload_len = BigEndian::read_u64(bytes);
+                track_assert_eq!(
+                    header.payload_len >> 63,
+                    0,
+                    bytecodec::ErrorKind::InvalidInput,
+                    "The most significant bit of a 64-bit payload length must be 0"
+                );
                 bytes = &bytes[8..];
             }
             _ => {}
@@ -244,10 +411,12 @@ impl Decode for FrameHeaderDecoder {
     fn requiring_bytes(&self) -> ByteCount {
         if self.completed {
             ByteCount::Finite(0)
+        } else if self.header.is_none() {
+            // The size of the extended part is unknown until the fixed part has been decoded
+            // (`extended_bytes` may still have the size of the previous header)
+            ByteCount::Unknown
         } else {
-            self.fixed_bytes
-                .requiring_bytes()
-                .add_for_decoding(self.extended_bytes.requiring_bytes())
+            self.extended_bytes.requiring_bytes()
         }
     }
 }
@@ -295,10 +464,20 @@ impl Decode for FramePayloadDecoder {
         let frame = match header.opcode {
             Opcode::ConnectionClose => {
                 track_assert_eq!(self.buf_start, 0, bytecodec::ErrorKind::InconsistentState);
-                track_assert!(self.buf_end >= 2, bytecodec::ErrorKind::InvalidInput);
-                let code = BigEndian::read_u16(&self.buf);
-                let reason = Vec::from(&self.buf[2..self.buf_end]);
-                Frame::ConnectionClose { code, reason }
+                if self.buf_end == 0 {
+                    Frame::ConnectionClose {
+                        code: None,
+                        reason: Vec::new(),
+                    }
+                } else {
+                    track_assert!(self.buf_end >= 2, bytecodec::ErrorKind::InvalidInput);
+                    let code = BigEndian::read_u16(&self.buf);
+                    let reason = Vec::from(&self.buf[2..self.buf_end]);
+                    Frame::ConnectionClose {
+                        code: Some(code),
+                        reason,
+                    }
+                }
             }
             Opcode::Ping => {
                 track_assert_eq!(self.buf_start, 0, bytecodec::ErrorKind::InconsistentState);
@@ -349,10 +528,10 @@ impl Decode for FramePayloadDecoder {
         }
     }
 }
-impl Default for FramePayloadDecoder {
-    fn default() -> Self {
+impl FramePayloadDecoder {
+    fn with_buffer_size(size: usize) -> Self {
         FramePayloadDecoder {
-            buf: vec![0; BUF_SIZE],
+            buf: vec![0; cmp::max(size, MIN_BUF_SIZE)],
             buf_start: 0,
             buf_end: 0,
             payload_offset: 0,
@@ -361,13 +540,140 @@ impl Default for FramePayloadDecoder {
         }
     }
 }
+impl Default for FramePayloadDecoder {
+    fn default() -> Self {
+        FramePayloadDecoder::with_buffer_size(BUF_SIZE)
+    }
+}
 
+/// Decoder of the WebSocket frames sent by clients.
+///
+/// Frames that break the rules of RFC 6455 on framing (reserved bits, masking, fragmentation,
+/// UTF-8 of text messages and close codes) make decoding fail, and the rule is reported by `violation`.
+/// After that, the rest of the input is ignored.
 #[derive(Debug, Default)]
 pub struct FrameDecoder {
     header: FrameHeaderDecoder,
     payload: FramePayloadDecoder,
+    last_data_opcode: Option<Opcode>,
+    message: Option<Opcode>,
+    message_len: u64,
+    max_message_size: Option<u64>,
+    data_bytes: u64,
+    validator: Option<FrameValidator>,
+    utf8: Utf8Validator,
+    violation: Option<Violation>,
 }
 impl FrameDecoder {
+    /// Makes a new `FrameDecoder` instance whose payload buffer has `size` bytes.
+    ///
+    /// The size is raised to 125 bytes (the maximum payload size of control frames) if smaller.
+    /// The default size is 4096 bytes.
+    pub fn with_buffer_size(size: usize) -> Self {
+        FrameDecoder {
+            payload: FramePayloadDecoder::with_buffer_size(size),
+            ..Default::default()
+        }
+    }
+
+    /// Makes the decoder also check that the reason of Close frames is valid UTF-8.
+    pub fn enable_validation(&mut self) {
+        self.validator = Some(FrameValidator);
+    }
+
+    /// Limits the total payload size of a (possibly fragmented) data message.
+    ///
+    /// A frame that would make its message exceed the limit fails to decode with
+    /// `Violation::MessageTooBig`, before its payload is decoded.
+    pub fn set_max_message_size(&mut self, size: u64) {
+        self.max_message_size = Some(size);
+    }
+
+    /// Returns the violation that made decoding fail.
+    pub fn violation(&self) -> Option<Violation> {
+        self.violation
+    }
+
+    // RSV bits must be 0 and clients must mask every frame (RFC 6455, 5.1-5.2), and control frames
+    // must not be fragmented or exceed 125 bytes but may be interleaved with the fragments of a
+    // message (5.4-5.5)
+    fn start_frame(&mut self, header: &FrameHeader) -> std::result::Result<(), Violation> {
+        if header.rsv != 0 {
+            // No extension is negotiated, so none of the reserved bits is defined
+            return Err(Violation::ReservedBits);
+        }
+        if header.mask.is_none() {
+            return Err(Violation::UnmaskedFrame);
+        }
+        match (header.opcode, self.message) {
+            (opcode, _) if opcode.is_control() && !header.fin => {
+                return Err(Violation::FragmentedControlFrame)
+            }
+            (opcode, _) if opcode.is_control() && header.payload_len > MAX_CONTROL_PAYLOAD_LEN => {
+                // Control payloads are held in the buffer as a whole, so they must fit in it
+                return Err(Violation::ControlFrameTooLarge);
+            }
+            (opcode, _) if opcode.is_control() => {}
+            (Opcode::ContinuationFrame, None) => return Err(Violation::UnexpectedContinuation),
+            (Opcode::ContinuationFrame, Some(_)) => {}
+            (_, Some(_)) => return Err(Violation::UnfinishedMessage),
+            (opcode, None) => {
+                self.message = Some(opcode);
+                self.message_len = 0;
+            }
+        }
+        if !header.opcode.is_control() {
+            self.message_len = self.message_len.saturating_add(header.payload_len);
+            if self
+                .max_message_size
+                .is_some_and(|max| self.message_len > max)
+            {
+                return Err(Violation::MessageTooBig);
+            }
+        }
+        Ok(())
+    }
+
+    fn check(&mut self, result: std::result::Result<(), Violation>) -> bytecodec::Result<()> {
+        if let Err(violation) = result {
+            self.violation = Some(violation);
+            track_panic!(bytecodec::ErrorKind::InvalidInput, "{}", violation);
+        }
+        Ok(())
+    }
+
+    /// Returns the total number of bytes written by `write_decoded_data`.
+    pub fn data_bytes(&self) -> u64 {
+        self.data_bytes
+    }
+
+    /// Returns the opcode of the last text or binary frame received.
+    pub fn last_data_opcode(&self) -> Option<Opcode> {
+        self.last_data_opcode
+    }
+
+    /// Writes (a part of) the unmasked payload decoded so far to `writer`.
+    ///
+    /// The payload of a data frame has to be written before the decoder accepts more of it.
+    /// The returned state is that of `writer`.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use bytecodec::{Decode, Eos};
+    /// use wstcp::frame::{Frame, FrameDecoder};
+    ///
+    /// // Binary frame with "foo" as payload (masked with zeros)
+    /// let bytes = [0x82, 0x83, 0, 0, 0, 0, b'f', b'o', b'o'];
+    /// let mut decoder = FrameDecoder::default();
+    /// decoder.decode(&bytes, Eos::new(false))?;
+    ///
+    /// let mut data = Vec::new();
+    /// decoder.write_decoded_data(&mut data)?;
+    /// assert_eq!(data, b"foo");
+    /// assert!(matches!(decoder.finish_decoding()?, Frame::Data));
+    /// # Ok::<(), Box<dyn std::error::Error>>(())
+    /// ```
     pub fn write_decoded_data<W: Write>(&mut self, mut writer: W) -> Result<StreamState> {
         if self.is_data_empty() {
             return Ok(StreamState::Normal);
@@ -384,6 +690,7 @@ impl FrameDecoder {
             }
             Ok(0) => Ok(StreamState::Eos),
             Ok(size) => {
+                self.data_bytes += size as u64;
                 self.payload.buf_start += size;
                 if self.payload.buf_start == self.payload.buf_end {
                     self.payload.buf_start = 0;
@@ -394,28 +701,73 @@ impl FrameDecoder {
         }
     }
 
+    /// Returns `true` if there is no decoded payload to be written by `write_decoded_data`.
     pub fn is_data_empty(&self) -> bool {
-        self.payload.header.as_ref().map_or(true, |h| {
-            h.opcode.is_control() || self.payload.buf_start == self.payload.buf_end
-        })
+        self.payload
+            .header
+            .as_ref()
+            .is_none_or(|h| h.opcode.is_control() || self.payload.buf_start == self.payload.buf_end)
     }
 }
 impl Decode for FrameDecoder {
     type Item = Frame;
 
     fn decode(&mut self, buf: &[u8], eos: Eos) -> bytecodec::Result<usize> {
+        if self.violation.is_some() {
+            // The connection is being failed, so the rest of the input is ignored
+            return Ok(buf.len());
+        }
+
         let mut offset = 0;
         if self.payload.header.is_none() {
             bytecodec_try_decode!(self.header, offset, buf, eos);
             let header = track!(self.header.finish_decoding())?;
+            let result = self.start_frame(&header);
+            track!(self.check(result))?;
+            if let Opcode::TextFrame | Opcode::BinaryFrame = header.opcode {
+                self.last_data_opcode = Some(header.opcode);
+            }
             self.payload.header = Some(header);
         }
-        bytecodec_try_decode!(self.payload, offset, buf, eos);
+
+        let start = self.payload.buf_end;
+        offset += track!(self.payload.decode(&buf[offset..], eos))?;
+        let is_data = self
+            .payload
+            .header
+            .as_ref()
+            .is_some_and(|h| !h.opcode.is_control());
+        if is_data && self.message == Some(Opcode::TextFrame) {
+            // Text is checked before being relayed (RFC 6455, 8.1)
+            let result = self
+                .utf8
+                .check(&self.payload.buf[start..self.payload.buf_end]);
+            track!(self.check(result))?;
+        }
         Ok(offset)
     }
 
     fn finish_decoding(&mut self) -> bytecodec::Result<Self::Item> {
-        track!(self.payload.finish_decoding())
+        let fin = self.payload.header.as_ref().is_some_and(|h| h.fin);
+        let frame = track!(self.payload.finish_decoding())?;
+        let result = match (&frame, &self.validator) {
+            (
+                Frame::ConnectionClose {
+                    code: Some(code), ..
+                },
+                _,
+            ) if !is_valid_close_code(*code) => Err(Violation::InvalidCloseCode(*code)),
+            (Frame::ConnectionClose { reason, .. }, Some(v)) => v.check_close_reason(reason),
+            (Frame::Data, _) if fin && self.message == Some(Opcode::TextFrame) => {
+                self.utf8.check_end()
+            }
+            _ => Ok(()),
+        };
+        if let (Frame::Data, true) = (&frame, fin) {
+            self.message = None;
+        }
+        track!(self.check(result))?;
+        Ok(frame)
     }
 
     fn requiring_bytes(&self) -> ByteCount {
@@ -425,10 +777,17 @@ impl Decode for FrameDecoder {
     }
 
     fn is_idle(&self) -> bool {
-        self.payload.is_idle()
+        self.violation.is_none() && self.payload.is_idle()
     }
 }
 
+/// Returns `true` if `code` may be sent in a Close frame (RFC 6455, 7.4).
+///
+/// Codes below 1000, the reserved ones (1004-1006 and 1015) and unassigned ones are invalid.
+pub(crate) fn is_valid_close_code(code: u16) -> bool {
+    matches!(code, 1000..=1003 | 1007..=1014 | 3000..=4999)
+}
+
 #[derive(Debug, Default, Clone, Copy)]
 struct ExtendedHeaderBytes {
     bytes: [u8; 12],
@@ -444,3 +803,362 @@ impl AsMut<[u8]> for ExtendedHeaderBytes {
         &mut self.bytes[..self.size]
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn header_split_into_single_bytes_works() {
+        let mut decoder = FrameHeaderDecoder::default();
+        let headers: [(&[u8], u64); 4] = [
+            (&[0x82, 0x85, 1, 2, 3, 4], 5),
+            (&[0x82, 0xFE, 0x01, 0x2C, 1, 2, 3, 4], 300),
+            (
+                &[0x82, 0xFF, 0, 0, 0, 0, 0, 1, 0x11, 0x70, 1, 2, 3, 4],
+                70000,
+            ),
+            (&[0x89, 0x00], 0),
+        ];
+        for (bytes, payload_len) in headers {
+            assert_eq!(decoder.requiring_bytes(), ByteCount::Unknown);
+            for (i, b) in bytes.iter().enumerate() {
+                assert!(!decoder.is_idle(), "byte {}", i);
+                let size = decoder.decode(&[*b], Eos::new(false)).unwrap();
+                assert_eq!(size, 1);
+                if i == 1 {
+                    let rest = (bytes.len() - 2) as u64;
+                    assert_eq!(decoder.requiring_bytes(), ByteCount::Finite(rest));
+                }
+            }
+            assert!(decoder.is_idle());
+            let header = decoder.finish_decoding().unwrap();
+            assert_eq!(header.payload_len, payload_len);
+            if bytes[1] & 0x80 != 0 {
+                assert_eq!(header.mask, Some([1, 2, 3, 4]));
+            } else {
+                assert_eq!(header.mask, None);
+            }
+        }
+    }
+
+    #[test]
+    fn frame_split_into_single_bytes_works() {
+        let mut decoder = FrameDecoder::default();
+        let payload = vec![7; 300];
+        let mask = [1, 2, 3, 4];
+        let mut bytes = vec![0x82, 0xFE, 0x01, 0x2C];
+        bytes.extend_from_slice(&mask);
+        bytes.extend(payload.iter().zip(mask.iter().cycle()).map(|(b, m)| b ^ m));
+
+        let mut decoded = Vec::new();
+        for b in &bytes {
+            let size = decoder.decode(&[*b], Eos::new(false)).unwrap();
+            assert_eq!(size, 1);
+            decoder.write_decoded_data(&mut decoded).unwrap();
+        }
+        assert!(decoder.is_idle());
+        assert!(matches!(decoder.finish_decoding().unwrap(), Frame::Data));
+        assert_eq!(decoded, payload);
+    }
+
+    #[test]
+    fn encode_data_with_opcode() {
+        let mut encoder = FrameEncoder::default();
+        let state = encoder
+            .start_encoding_data(&b"foo"[..], Opcode::TextFrame)
+            .unwrap();
+        assert_eq!(state, StreamState::Normal);
+
+        let mut buf = [0; 8];
+        let size = encoder.encode(&mut buf, Eos::new(false)).unwrap();
+        assert_eq!(&buf[..size], b"\x81\x03foo");
+        assert!(encoder.is_idle());
+    }
+
+    #[test]
+    fn max_data_size_works() {
+        let mut encoder = FrameEncoder::default();
+        encoder.set_max_data_size(2);
+
+        let mut reader = &b"foo"[..];
+        let mut buf = [0; 8];
+        encoder
+            .start_encoding_data(&mut reader, Opcode::BinaryFrame)
+            .unwrap();
+        let size = encoder.encode(&mut buf, Eos::new(false)).unwrap();
+        assert_eq!(&buf[..size], b"\x82\x02fo");
+
+        encoder
+            .start_encoding_data(&mut reader, Opcode::BinaryFrame)
+            .unwrap();
+        let size = encoder.encode(&mut buf, Eos::new(false)).unwrap();
+        assert_eq!(&buf[..size], b"\x82\x01o");
+    }
+
+    #[test]
+    fn buffer_size_works() {
+        // Payloads of control frames always fit
+        let mut encoder = FrameEncoder::with_buffer_size(1);
+        let data = vec![0; 125];
+        encoder.start_encoding(Frame::Ping { data }).unwrap();
+
+        let mut buf = vec![0; 256];
+        let size = encoder.encode(&mut buf, Eos::new(false)).unwrap();
+        assert_eq!(size, 2 + 125);
+
+        let data = [7; 200];
+        encoder
+            .start_encoding_data(&data[..], Opcode::BinaryFrame)
+            .unwrap();
+        let size = encoder.encode(&mut buf, Eos::new(false)).unwrap();
+        assert_eq!(&buf[..2], b"\x82\x7d");
+        assert_eq!(size, 2 + 125);
+    }
+
+    #[test]
+    fn mask_outgoing_works() {
+        let mut encoder = FrameEncoder::default();
+        encoder.set_mask_outgoing(true);
+        encoder
+            .start_encoding_data(&b"hello"[..], Opcode::TextFrame)
+            .unwrap();
+
+        let mut buf = [0; 16];
+        let size = encoder.encode(&mut buf, Eos::new(false)).unwrap();
+        assert_eq!(size, 2 + 4 + 5);
+        assert_eq!(buf[..2], [0x81, MASK_FLAG | 5]);
+        let mask = &buf[2..6];
+        let payload = buf[6..size]
+            .iter()
+            .zip(mask.iter().cycle())
+            .map(|(b, m)| b ^ m)
+            .collect::<Vec<_>>();
+        assert_eq!(payload, b"hello");
+
+        // The decoder unmasks the frame
+        let mut decoder = FrameDecoder::default();
+        decoder.decode(&buf[..size], Eos::new(false)).unwrap();
+        let mut data = Vec::new();
+        decoder.write_decoded_data(&mut data).unwrap();
+        assert_eq!(data, b"hello");
+    }
+
+    #[test]
+    fn decoder_remembers_last_data_opcode() {
+        let mut decoder = FrameDecoder::default();
+        assert_eq!(decoder.last_data_opcode(), None);
+
+        // masked text frame (FIN=1, payload="a") followed by a masked ping
+        let bytes = [0x81, 0x81, 0, 0, 0, 0, b'a', 0x89, 0x80, 0, 0, 0, 0];
+        let size = decoder.decode(&bytes, Eos::new(false)).unwrap();
+        assert_eq!(decoder.last_data_opcode(), Some(Opcode::TextFrame));
+
+        let mut sink = Vec::new();
+        decoder.write_decoded_data(&mut sink).unwrap();
+        assert_eq!(sink, b"a");
+        decoder.finish_decoding().unwrap();
+
+        decoder.decode(&bytes[size..], Eos::new(false)).unwrap();
+        assert!(matches!(decoder.finish_decoding(), Ok(Frame::Ping { .. })));
+        assert_eq!(decoder.last_data_opcode(), Some(Opcode::TextFrame));
+    }
+
+    #[test]
+    fn fragmented_message_works() {
+        // Three masked binary fragments ("ab", "cd", "ef") with a ping between the first two
+        let bytes = [
+            &[0x02, 0x82, 0, 0, 0, 0, b'a', b'b'][..],
+            &[0x89, 0x80, 0, 0, 0, 0],
+            &[0x00, 0x82, 0, 0, 0, 0, b'c', b'd'],
+            &[0x80, 0x82, 0, 0, 0, 0, b'e', b'f'],
+        ]
+        .concat();
+        let mut decoder = FrameDecoder::default();
+        let mut offset = 0;
+        let mut data = Vec::new();
+        let mut frames = Vec::new();
+        while offset < bytes.len() {
+            offset += decoder.decode(&bytes[offset..], Eos::new(false)).unwrap();
+            decoder.write_decoded_data(&mut data).unwrap();
+            if decoder.is_idle() {
+                frames.push(decoder.finish_decoding().unwrap());
+            }
+        }
+        assert_eq!(data, b"abcdef");
+        assert!(matches!(
+            frames[..],
+            [Frame::Data, Frame::Ping { .. }, Frame::Data, Frame::Data]
+        ));
+        assert_eq!(decoder.last_data_opcode(), Some(Opcode::BinaryFrame));
+
+        // A new message can begin after the final fragment
+        decoder
+            .decode(&[0x81, 0x80, 0, 0, 0, 0], Eos::new(false))
+            .unwrap();
+        assert!(decoder.finish_decoding().is_ok());
+    }
+
+    #[test]
+    fn large_payload_is_streamed() {
+        const SIZE: usize = 1024 * 1024;
+        let mask = [1, 2, 3, 4];
+        let mut bytes = vec![0x82, MASK_FLAG | 127];
+        bytes.extend_from_slice(&(SIZE as u64).to_be_bytes());
+        bytes.extend_from_slice(&mask);
+        bytes.extend((0..SIZE).map(|i| (i % 251) as u8 ^ mask[i % 4]));
+
+        let mut decoder = FrameDecoder::default();
+        let mut offset = 0;
+        let mut data = Vec::new();
+        while !decoder.is_idle() {
+            offset += decoder.decode(&bytes[offset..], Eos::new(false)).unwrap();
+            decoder.write_decoded_data(&mut data).unwrap();
+        }
+        assert_eq!(offset, bytes.len());
+        assert!(matches!(decoder.finish_decoding(), Ok(Frame::Data)));
+        assert!(data.iter().enumerate().all(|(i, &b)| b == (i % 251) as u8));
+        assert_eq!(data.len(), SIZE);
+        assert_eq!(decoder.data_bytes(), SIZE as u64);
+    }
+
+    #[test]
+    fn interleaved_ping_does_not_corrupt_unmasking() {
+        fn masked(first_byte: u8, mask: [u8; 4], payload: &[u8]) -> Vec<u8> {
+            let mut frame = vec![first_byte, MASK_FLAG | payload.len() as u8];
+            frame.extend_from_slice(&mask);
+            frame.extend(payload.iter().zip(mask.iter().cycle()).map(|(b, m)| b ^ m));
+            frame
+        }
+        let bytes = [
+            masked(0x01, [1, 2, 3, 4], b"hello"),
+            masked(0x89, [5, 6, 7, 8], b"abc"),
+            masked(0x80, [9, 10, 11, 12], b" world"),
+        ]
+        .concat();
+
+        // Fed one byte at a time, so that each frame ends at an odd mask offset
+        let mut decoder = FrameDecoder::default();
+        let mut data = Vec::new();
+        let mut pings = Vec::new();
+        for b in &bytes {
+            assert_eq!(decoder.decode(&[*b], Eos::new(false)).unwrap(), 1);
+            decoder.write_decoded_data(&mut data).unwrap();
+            if decoder.is_idle() {
+                if let Frame::Ping { data } = decoder.finish_decoding().unwrap() {
+                    pings.push(data);
+                }
+            }
+        }
+        assert_eq!(data, b"hello world");
+        assert_eq!(pings, [b"abc"]);
+    }
+
+    #[test]
+    fn misordered_fragments_are_rejected() {
+        assert!(decode_frame(&[0x80, 0x80, 0, 0, 0, 0]).is_err());
+
+        let mut decoder = FrameDecoder::default();
+        decoder
+            .decode(&[0x02, 0x80, 0, 0, 0, 0], Eos::new(false))
+            .unwrap();
+        decoder.finish_decoding().unwrap();
+        assert!(decoder
+            .decode(&[0x81, 0x80, 0, 0, 0, 0], Eos::new(false))
+            .is_err());
+        assert_eq!(decoder.violation(), Some(Violation::UnfinishedMessage));
+    }
+
+    fn encode_frame(frame: Frame) -> Vec<u8> {
+        let mut encoder = FrameEncoder::default();
+        encoder.start_encoding(frame).unwrap();
+        let mut buf = vec![0; 16];
+        let size = encoder.encode(&mut buf, Eos::new(false)).unwrap();
+        assert!(encoder.is_idle());
+        buf.truncate(size);
+        buf
+    }
+
+    fn decode_frame(bytes: &[u8]) -> bytecodec::Result<Frame> {
+        let mut decoder = FrameDecoder::default();
+        let size = track!(decoder.decode(bytes, Eos::new(false)))?;
+        assert_eq!(size, bytes.len());
+        track!(decoder.finish_decoding())
+    }
+
+    #[test]
+    fn empty_close_frame() {
+        let frame = decode_frame(&[0x88, 0x80, 1, 2, 3, 4]).unwrap();
+        assert!(
+            matches!(frame, Frame::ConnectionClose { code: None, ref reason } if reason.is_empty())
+        );
+
+        let bytes = encode_frame(Frame::ConnectionClose {
+            code: None,
+            reason: Vec::new(),
+        });
+        assert_eq!(bytes, [0x88, 0x00]);
+    }
+
+    #[test]
+    fn code_only_close_frame() {
+        // 1000 (0x03E8) masked with [0, 0, 0, 0]
+        let frame = decode_frame(&[0x88, 0x82, 0, 0, 0, 0, 0x03, 0xE8]).unwrap();
+        assert!(
+            matches!(frame, Frame::ConnectionClose { code: Some(1000), ref reason } if reason.is_empty())
+        );
+
+        let bytes = encode_frame(Frame::ConnectionClose {
+            code: Some(1000),
+            reason: Vec::new(),
+        });
+        assert_eq!(bytes, [0x88, 0x02, 0x03, 0xE8]);
+    }
+
+    #[test]
+    fn invalid_close_code_is_rejected() {
+        for code in [999u16, 1004, 1005, 1006, 1015, 2000, 5000] {
+            let [hi, lo] = code.to_be_bytes();
+            assert!(decode_frame(&[0x88, 0x82, 0, 0, 0, 0, hi, lo]).is_err());
+        }
+        for code in [1000u16, 1003, 1007, 1014, 3000, 4999] {
+            let [hi, lo] = code.to_be_bytes();
+            assert!(decode_frame(&[0x88, 0x82, 0, 0, 0, 0, hi, lo]).is_ok());
+        }
+    }
+
+    #[test]
+    fn one_byte_close_payload_is_rejected() {
+        assert!(decode_frame(&[0x88, 0x81, 0, 0, 0, 0, 0x03]).is_err());
+    }
+
+    #[test]
+    fn fragmented_control_frame_is_rejected() {
+        // Ping with FIN unset
+        assert!(decode_frame(&[0x09, 0x80, 0, 0, 0, 0]).is_err());
+        assert!(decode_frame(&[0x89, 0x80, 0, 0, 0, 0]).is_ok());
+    }
+
+    #[test]
+    fn too_large_control_frame_is_rejected() {
+        let mut bytes = vec![0x89, 0xFE, 0, 126, 0, 0, 0, 0];
+        bytes.extend_from_slice(&[0; 126]);
+        let mut decoder = FrameDecoder::default();
+        assert!(decoder.decode(&bytes, Eos::new(false)).is_err());
+        assert_eq!(decoder.violation(), Some(Violation::ControlFrameTooLarge));
+    }
+
+    #[test]
+    fn reserved_bits_are_rejected() {
+        // RSV1 is set on a masked binary frame
+        assert!(decode_frame(&[0xC2, 0x81, 0, 0, 0, 0, b'x']).is_err());
+    }
+
+    #[test]
+    fn payload_length_with_msb_set_is_rejected() {
+        let mut bytes = vec![0x82, 0xFF, 0x80, 0, 0, 0, 0, 0, 0, 0];
+        bytes.extend_from_slice(&[1, 2, 3, 4]);
+        let mut decoder = FrameDecoder::default();
+        assert!(decoder.decode(&bytes, Eos::new(false)).is_err());
+    }
+}
@@ -11,15 +11,32 @@ extern crate bytecodec;
 #[macro_use]
 extern crate trackable;
 
+pub use channel::{
+    AcceptedFrameTypes, ChannelConfig, ClientAddressHeader, FrameType, HandshakeOrder,
+    HandshakeRejection, LogFormat, ResponseHeaderHook,
+};
+pub use cidr::IpCidr;
 pub use error::{Error, ErrorKind};
-pub use server::ProxyServer;
+pub use observer::{ChannelObserver, Decision};
+pub use route::Route;
+pub use server::{PauseHandle, ProxyServer, ProxyServerBuilder, ShutdownHandle};
 
+mod backend;
 mod channel;
+mod cidr;
 mod error;
-mod frame;
+pub mod frame;
+mod observer;
 mod opcode;
+mod registry;
+mod resume;
+mod route;
 mod server;
+#[cfg(test)]
+mod testing;
 mod util;
+mod validator;
+mod webhook;
 
 /// This crate specific `Result` type.
 pub type Result<T> = std::result::Result<T, Error>;
@@ -2,19 +2,437 @@ extern crate clap;
 #[macro_use]
 extern crate trackable;
 
-use async_std::net::TcpListener;
+use async_std::net::{TcpListener, TcpStream};
 use clap::{Parser, ValueEnum};
+use regex::bytes::Regex;
+use std::io::Write;
 use std::net::SocketAddr;
-use wstcp::{Error, ProxyServer};
+#[cfg(unix)]
+use std::os::unix::io::{FromRawFd, RawFd};
+use std::time::Duration;
+use trackable::error::ErrorKindExt;
+use wstcp::{
+    AcceptedFrameTypes, ChannelConfig, ClientAddressHeader, Error, ErrorKind, FrameType,
+    HandshakeOrder, IpCidr, LogFormat, ProxyServer, Route,
+};
+
+const ACCESS_LOG_TARGET: &str = "wstcp::access";
+const PROBE_TIMEOUT: Duration = Duration::from_secs(5);
+const MAX_MESSAGE_PAYLOAD_SIZE: usize = 4096;
 
 #[derive(Parser)]
 struct Args {
-    /// The TCP address of the real server.
-    real_server_addr: SocketAddr,
+    /// The TCP address of the real server (serves the requests matching no `--route`).
+    #[clap(required_unless_present = "route")]
+    real_server_addr: Option<SocketAddr>,
+
+    /// Routes the requests whose target starts with PREFIX to the real server at ADDR (can be
+    /// specified multiple times; the longest matching prefix wins).
+    ///
+    /// Requests matching no route are rejected with `404 Not Found` unless the real server address
+    /// is given.
+    #[clap(long, value_name = "PREFIX=ADDR")]
+    route: Vec<Route>,
 
     /// TCP address to which the WebSocket proxy bind.
     #[clap(long, default_value = "0.0.0.0:13892")]
     bind_addr: SocketAddr,
+
+    /// Accepts connections on an inherited listening socket (e.g., systemd socket activation)
+    /// instead of binding `--bind-addr`.
+    #[cfg(unix)]
+    #[clap(long, value_name = "FD", conflicts_with = "bind_addr")]
+    listen_fd: Option<RawFd>,
+
+    /// Value of the `Server` header added to handshake responses (omitted by default).
+    #[clap(long)]
+    server_header: Option<String>,
+
+    /// Adds a `Date` header to handshake responses.
+    #[clap(long)]
+    emit_date: bool,
+
+    /// Type of the frames used to relay data from the real server to the client.
+    ///
+    /// `mirror` uses the type of the last data frame sent by the client.
+    #[clap(long, value_enum, default_value = "binary")]
+    frame_type: FrameTypeArg,
+
+    /// Body of the `503 Service Unavailable` response sent when the real server cannot be reached.
+    #[clap(long)]
+    unavailable_body: Option<String>,
+
+    /// Network interface that connections to the real server egress from (Linux only).
+    #[clap(long, value_name = "NAME")]
+    backend_connect_interface: Option<String>,
+
+    /// Access log format (`clf` emits a Common Log Format line per connection).
+    #[clap(long, value_enum, default_value = "plain")]
+    log_format: LogFormatArg,
+
+    /// Log level (`RUST_LOG` takes precedence if set).
+    #[clap(long, value_enum, default_value = "info")]
+    log_level: LogLevelArg,
+
+    /// Size in bytes of each buffer of a proxy channel.
+    #[clap(
+        long,
+        value_name = "BYTES",
+        default_value_t = 4096,
+        value_parser = clap::value_parser!(u32).range(1..)
+    )]
+    buffer_size: u32,
+
+    /// Maximum payload size of the frames used to relay data from the real server to the client.
+    #[clap(long, value_name = "BYTES", value_parser = clap::value_parser!(u32).range(1..))]
+    max_outgoing_frame_size: Option<u32>,
+
+    /// Time window in milliseconds for coalescing outgoing WebSocket writes.
+    #[clap(long, value_name = "MILLISECONDS")]
+    write_coalescing_window: Option<u64>,
+
+    /// Maximum time in milliseconds from accepting a connection to relaying its first data byte.
+    #[clap(long, value_name = "MILLISECONDS")]
+    time_to_first_byte_budget: Option<u64>,
+
+    /// Regular expression that the client's first data frame must fully match before the real
+    /// server is connected (the frame itself is not relayed).
+    #[clap(long, value_name = "REGEX", value_parser = parse_app_handshake_pattern)]
+    app_handshake_pattern: Option<Regex>,
+
+    /// Request header carrying a session id; a new connection with the id of an active one
+    /// closes the older connection.
+    #[clap(long, value_name = "NAME")]
+    session_header: Option<String>,
+
+    /// Close code sent to a connection replaced by a newer one with the same session id.
+    #[clap(long, value_name = "CODE", default_value_t = 4000)]
+    session_close_code: u16,
+
+    /// URL (`http://HOST[:PORT][/PATH]`) that a JSON summary of each closed connection is
+    /// posted to.
+    #[clap(long, value_name = "URL")]
+    close_webhook: Option<String>,
+
+    /// Request header carrying an absolute deadline (Unix epoch seconds) at which the
+    /// connection is closed.
+    #[clap(long, value_name = "NAME")]
+    deadline_header: Option<String>,
+
+    /// Idle time in seconds after which TCP keepalive probes are sent to the real server.
+    #[clap(long, value_name = "SECONDS", value_parser = clap::value_parser!(u64).range(1..))]
+    backend_liveness_interval: Option<u64>,
+
+    /// Maximum time in milliseconds to wait for the closing handshake before dropping the
+    /// connection.
+    #[clap(long, value_name = "MILLISECONDS")]
+    close_timeout: Option<u64>,
+
+    /// Probe sent to the real server right after connecting (requires
+    /// `--backend-ready-response`).
+    #[clap(long, value_name = "BYTES", requires = "backend_ready_response")]
+    backend_ready_probe: Option<String>,
+
+    /// Response that the real server has to send back to the probe before the handshake
+    /// succeeds.
+    #[clap(long, value_name = "BYTES", requires = "backend_ready_probe")]
+    backend_ready_response: Option<String>,
+
+    /// Maximum number of bytes read from a client per poll, to keep a fast client from
+    /// monopolizing the executor.
+    #[clap(long, value_name = "BYTES", value_parser = clap::value_parser!(u32).range(1..))]
+    max_read_per_poll: Option<u32>,
+
+    /// Maximum number of connections accepted per wakeup of the server, to keep an accept storm
+    /// from starving the established connections.
+    #[clap(long, value_name = "COUNT", value_parser = clap::value_parser!(u32).range(1..))]
+    max_accept_per_poll: Option<u32>,
+
+    /// Rebinds `--bind-addr` (retrying with backoff) if the listening socket is closed
+    /// unexpectedly, instead of exiting.
+    #[clap(long)]
+    rebind_on_close: bool,
+
+    /// Maximum number of concurrent connections; handshake requests beyond it are answered with
+    /// `503 Service Unavailable` (and once as many are waiting for it, new connections are left
+    /// in the listen backlog).
+    #[clap(long, value_name = "COUNT", value_parser = clap::value_parser!(u32).range(1..))]
+    max_connections: Option<u32>,
+
+    /// Maximum seconds to wait for the active connections to close on shutdown (SIGINT, SIGTERM
+    /// or `--run-for`); by default, the server waits until all of them have closed.
+    #[clap(long, value_name = "SECONDS")]
+    shutdown_grace_period: Option<u64>,
+
+    /// Shuts the server down gracefully after the given number of seconds.
+    #[clap(long, value_name = "SECONDS")]
+    run_for: Option<u64>,
+
+    /// Sends the client's request path to the real server as a `Path: <PATH>\r\n` line before
+    /// relaying any data.
+    #[clap(long)]
+    send_path_header: bool,
+
+    /// Header carrying the client's address sent to the real server before any other data
+    /// (`proxy-v1` is a PROXY protocol version 1 line).
+    #[clap(long, value_enum, default_value = "none")]
+    client_address_header: ClientAddressHeaderArg,
+
+    /// Masks the frames sent to clients (violates RFC 6455; for diagnosing client bugs only).
+    #[clap(long, hide = true)]
+    mask_outgoing: bool,
+
+    /// Name of the request header carrying a resume token.
+    ///
+    /// If a client disappears without closing, its real server connection is kept for
+    /// `--resume-window` seconds and handed over to a client reconnecting with the same token.
+    #[clap(long)]
+    resume_header: Option<String>,
+
+    /// Seconds to keep the real server connection of a disappeared client for resumption.
+    #[clap(long, value_name = "SECONDS", default_value_t = 30)]
+    resume_window: u64,
+
+    /// Types of the data frames accepted from clients; other types close the connection
+    /// with code 1003.
+    #[clap(long, value_enum, default_value = "any")]
+    accept_frame_types: AcceptFrameTypesArg,
+
+    /// Payload of the heartbeat data frames sent to idle clients (requires
+    /// `--client-heartbeat-interval`).
+    #[clap(
+        long,
+        value_name = "PAYLOAD",
+        value_parser = parse_message_payload,
+        requires = "client_heartbeat_interval"
+    )]
+    client_heartbeat: Option<String>,
+
+    /// Seconds without data sent to a client after which a heartbeat frame is sent.
+    #[clap(
+        long,
+        value_name = "SECONDS",
+        value_parser = clap::value_parser!(u64).range(1..),
+        requires = "client_heartbeat"
+    )]
+    client_heartbeat_interval: Option<u64>,
+
+    /// Maximum length of the request target of handshake requests (longer ones are rejected
+    /// with `414 URI Too Long`).
+    #[clap(long, value_name = "BYTES")]
+    max_uri_length: Option<usize>,
+
+    /// Validates the frames sent by clients against RFC 6455 instead of relaying them (the real
+    /// server is never connected); violations close the connection with the matching close code.
+    #[clap(long)]
+    validate_only: bool,
+
+    /// Logs a warning when client data has been waiting for the real server to accept it for
+    /// the given number of milliseconds.
+    #[clap(long, value_name = "MILLISECONDS")]
+    backend_lag_warning: Option<u64>,
+
+    /// Maximum total payload size of a (possibly fragmented) message sent by a client; larger
+    /// messages close the connection with code 1009.
+    #[clap(long, value_name = "BYTES")]
+    max_message_size: Option<usize>,
+
+    /// Seconds between the Ping frames sent to clients; a client that has not answered the
+    /// previous Ping with a Pong is disconnected with code 1001.
+    #[clap(
+        long,
+        value_name = "SECONDS",
+        value_parser = clap::value_parser!(u64).range(1..)
+    )]
+    keepalive_interval: Option<u64>,
+
+    /// Client address range (e.g., `10.0.0.0/8`) allowed to connect; if given, clients outside
+    /// all allowed ranges are refused (can be specified multiple times).
+    #[clap(long, value_name = "CIDR")]
+    allow_cidr: Vec<IpCidr>,
+
+    /// Client address range refused at accept time, even if it is also allowed by
+    /// `--allow-cidr` (can be specified multiple times).
+    #[clap(long, value_name = "CIDR")]
+    deny_cidr: Vec<IpCidr>,
+
+    /// Seconds without reading anything from either the client or the real server after which
+    /// the connection is closed with code 1001.
+    #[clap(
+        long,
+        value_name = "SECONDS",
+        value_parser = clap::value_parser!(u64).range(1..)
+    )]
+    idle_timeout: Option<u64>,
+
+    /// Microseconds of polling a channel may take within `--poll-time-window`; channels
+    /// exceeding it are logged.
+    #[clap(long, value_name = "MICROSECONDS", requires = "poll_time_window")]
+    poll_time_budget: Option<u64>,
+
+    /// Seconds over which the time spent polling a channel is accumulated.
+    #[clap(
+        long,
+        value_name = "SECONDS",
+        value_parser = clap::value_parser!(u64).range(1..),
+        requires = "poll_time_budget"
+    )]
+    poll_time_window: Option<u64>,
+
+    /// Closes channels exceeding `--poll-time-budget` with code 1008.
+    #[clap(long, requires = "poll_time_budget")]
+    close_over_poll_time_budget: bool,
+
+    /// Whether the handshake response is sent after connecting to the real server
+    /// (`backend-first`) or immediately (`respond-first`; an unreachable real server then
+    /// closes the connection with code 1011).
+    #[clap(long, value_enum, default_value = "backend-first")]
+    handshake_order: HandshakeOrderArg,
+
+    /// Maximum number of Pong frames sent to a client; further Ping frames are ignored.
+    #[clap(long, value_name = "COUNT")]
+    max_pongs: Option<u64>,
+
+    /// Closes the connection with code 1008 when a Ping frame arrives after `--max-pongs` Pong
+    /// frames have been sent.
+    #[clap(long, requires = "max_pongs")]
+    close_over_max_pongs: bool,
+
+    /// Logs the lifecycle events of only one in N connections at the info level (the others are
+    /// logged at the debug level; warnings and errors are always logged).
+    #[clap(long, value_name = "N", value_parser = clap::value_parser!(u64).range(1..))]
+    log_sample_rate: Option<u64>,
+
+    /// Payload of the data frame sent to a client right before the Close frame when its message
+    /// exceeds `--max-message-size`.
+    #[clap(
+        long,
+        value_name = "PAYLOAD",
+        value_parser = parse_message_payload,
+        requires = "max_message_size"
+    )]
+    overflow_notice: Option<String>,
+
+    /// Subprotocol that may be selected through the `Sec-WebSocket-Protocol` header (can be
+    /// specified multiple times).
+    #[clap(long, value_name = "NAME")]
+    subprotocol: Vec<String>,
+
+    /// Rejects handshake requests offering only subprotocols not given by `--subprotocol`.
+    #[clap(long, requires = "subprotocol")]
+    require_subprotocol: bool,
+
+    /// Origin from which handshake requests are accepted (can be specified multiple times).
+    ///
+    /// `*` matches any sequence of characters (e.g., `https://*.example.com`). If given, requests
+    /// whose `Origin` header is missing or not allowed are rejected with `403 Forbidden`.
+    #[clap(long, value_name = "ORIGIN")]
+    allow_origin: Vec<String>,
+
+    /// Header added to every `101 Switching Protocols` response (can be specified multiple
+    /// times).
+    #[clap(long, value_name = "NAME:VALUE", value_parser = parse_response_header)]
+    response_header: Vec<(String, String)>,
+
+    /// Checks that the bind address is free and the real server is reachable, then exits
+    /// without serving traffic.
+    #[clap(long)]
+    probe_only: bool,
+}
+
+fn parse_app_handshake_pattern(s: &str) -> Result<Regex, regex::Error> {
+    Regex::new(&format!("^(?:{})$", s))
+}
+
+fn parse_response_header(s: &str) -> Result<(String, String), String> {
+    let (name, value) = s.split_once(':').ok_or("must be in the form NAME:VALUE")?;
+    let value = value.trim();
+    httpcodec::HeaderField::new(name, value).map_err(|e| e.to_string())?;
+    Ok((name.to_owned(), value.to_owned()))
+}
+
+fn parse_message_payload(s: &str) -> Result<String, String> {
+    if s.len() > MAX_MESSAGE_PAYLOAD_SIZE {
+        return Err(format!(
+            "must be at most {} bytes",
+            MAX_MESSAGE_PAYLOAD_SIZE
+        ));
+    }
+    Ok(s.to_owned())
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum LogFormatArg {
+    Plain,
+    Clf,
+}
+impl From<LogFormatArg> for LogFormat {
+    fn from(f: LogFormatArg) -> Self {
+        match f {
+            LogFormatArg::Plain => LogFormat::Plain,
+            LogFormatArg::Clf => LogFormat::Clf,
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum FrameTypeArg {
+    Binary,
+    Text,
+    Mirror,
+}
+impl From<FrameTypeArg> for FrameType {
+    fn from(f: FrameTypeArg) -> Self {
+        match f {
+            FrameTypeArg::Binary => FrameType::Binary,
+            FrameTypeArg::Text => FrameType::Text,
+            FrameTypeArg::Mirror => FrameType::Mirror,
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum AcceptFrameTypesArg {
+    Any,
+    Binary,
+    Text,
+}
+impl From<AcceptFrameTypesArg> for AcceptedFrameTypes {
+    fn from(f: AcceptFrameTypesArg) -> Self {
+        match f {
+            AcceptFrameTypesArg::Any => AcceptedFrameTypes::Any,
+            AcceptFrameTypesArg::Binary => AcceptedFrameTypes::Binary,
+            AcceptFrameTypesArg::Text => AcceptedFrameTypes::Text,
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum ClientAddressHeaderArg {
+    None,
+    ProxyV1,
+}
+impl From<ClientAddressHeaderArg> for ClientAddressHeader {
+    fn from(f: ClientAddressHeaderArg) -> Self {
+        match f {
+            ClientAddressHeaderArg::None => ClientAddressHeader::None,
+            ClientAddressHeaderArg::ProxyV1 => ClientAddressHeader::ProxyV1,
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum HandshakeOrderArg {
+    BackendFirst,
+    RespondFirst,
+}
+impl From<HandshakeOrderArg> for HandshakeOrder {
+    fn from(f: HandshakeOrderArg) -> Self {
+        match f {
+            HandshakeOrderArg::BackendFirst => HandshakeOrder::BackendFirst,
+            HandshakeOrderArg::RespondFirst => HandshakeOrder::RespondFirst,
+        }
+    }
 }
 
 #[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
@@ -24,22 +442,311 @@ enum LogLevelArg {
     Warning,
     Error,
 }
+impl From<LogLevelArg> for log::LevelFilter {
+    fn from(f: LogLevelArg) -> Self {
+        match f {
+            LogLevelArg::Debug => log::LevelFilter::Debug,
+            LogLevelArg::Info => log::LevelFilter::Info,
+            LogLevelArg::Warning => log::LevelFilter::Warn,
+            LogLevelArg::Error => log::LevelFilter::Error,
+        }
+    }
+}
 
 fn main() -> trackable::result::TopLevelResult {
-    env_logger::init();
-
     let args = Args::parse();
+    init_logger(args.log_level, args.log_format);
+
     let bind_addr = args.bind_addr;
-    let tcp_server_addr = args.real_server_addr;
+    let run_for = args.run_for;
+    let max_accept_per_poll = args.max_accept_per_poll;
+    let rebind_on_close = args.rebind_on_close;
+    let max_connections = args.max_connections;
+    let shutdown_grace_period = args.shutdown_grace_period;
+    let mut routes = args.route;
+    if let (Some(addr), false) = (args.real_server_addr, routes.is_empty()) {
+        routes.push(Route::new("/", addr));
+    }
+    // With routes, the address given to the server is never connected to directly
+    let tcp_server_addr = args.real_server_addr.unwrap_or_else(|| routes[0].addr());
+    if args.probe_only {
+        let mut addrs = routes.iter().map(Route::addr).collect::<Vec<_>>();
+        if addrs.is_empty() {
+            addrs.push(tcp_server_addr);
+        }
+        track!(async_std::task::block_on(probe(bind_addr, &addrs)))?;
+        return Ok(());
+    }
 
+    let config = ChannelConfig {
+        server_header: args.server_header,
+        emit_date: args.emit_date,
+        frame_type: args.frame_type.into(),
+        unavailable_body: args.unavailable_body,
+        backend_interface: args.backend_connect_interface,
+        log_format: args.log_format.into(),
+        buffer_size: Some(args.buffer_size as usize),
+        max_outgoing_frame_size: args.max_outgoing_frame_size.map(|n| n as usize),
+        write_coalescing_window: args.write_coalescing_window.map(Duration::from_millis),
+        time_to_first_byte_budget: args.time_to_first_byte_budget.map(Duration::from_millis),
+        app_handshake_pattern: args.app_handshake_pattern,
+        session_header: args.session_header,
+        session_close_code: Some(args.session_close_code),
+        close_webhook: args.close_webhook,
+        deadline_header: args.deadline_header,
+        backend_liveness_interval: args.backend_liveness_interval.map(Duration::from_secs),
+        close_timeout: args.close_timeout.map(Duration::from_millis),
+        backend_ready_probe: args
+            .backend_ready_probe
+            .zip(args.backend_ready_response)
+            .map(|(probe, response)| (probe.into_bytes(), response.into_bytes())),
+        max_read_per_poll: args.max_read_per_poll.map(|n| n as usize),
+        send_path_header: args.send_path_header,
+        client_address_header: args.client_address_header.into(),
+        mask_outgoing_frames: args.mask_outgoing,
+        resume_header: args.resume_header,
+        resume_window: Some(Duration::from_secs(args.resume_window)),
+        accepted_frame_types: args.accept_frame_types.into(),
+        client_heartbeat: args
+            .client_heartbeat
+            .zip(args.client_heartbeat_interval)
+            .map(|(payload, secs)| (payload.into_bytes(), Duration::from_secs(secs))),
+        max_uri_length: args.max_uri_length,
+        validate_only: args.validate_only,
+        response_header_hook: None,
+        observer: None,
+        extra_response_headers: args.response_header,
+        backend_lag_warning: args.backend_lag_warning.map(Duration::from_millis),
+        max_message_size: args.max_message_size,
+        keepalive_interval: args.keepalive_interval.map(Duration::from_secs),
+        allow_cidrs: args.allow_cidr,
+        deny_cidrs: args.deny_cidr,
+        idle_timeout: args.idle_timeout.map(Duration::from_secs),
+        poll_time_budget: args
+            .poll_time_budget
+            .zip(args.poll_time_window)
+            .map(|(budget, window)| (Duration::from_micros(budget), Duration::from_secs(window))),
+        close_over_poll_time_budget: args.close_over_poll_time_budget,
+        handshake_order: args.handshake_order.into(),
+        max_pongs: args.max_pongs,
+        log_sample_rate: args.log_sample_rate,
+        close_over_max_pongs: args.close_over_max_pongs,
+        overflow_notice: args.overflow_notice.map(String::into_bytes),
+        subprotocols: args.subprotocol,
+        require_subprotocol: args.require_subprotocol,
+        allowed_origins: args.allow_origin,
+        routes,
+    };
+
+    // Signals must be blocked before the runtime threads are spawned (they inherit the mask)
+    #[cfg(unix)]
+    let signals = block_signals();
+
+    #[cfg(unix)]
+    let inherited_listener = args.listen_fd.map(|fd| {
+        track!(listener_from_fd(fd)).expect("failed to listen on the given file descriptor")
+    });
+    #[cfg(not(unix))]
+    let inherited_listener = None;
     async_std::task::block_on(async {
-        let listener = track!(TcpListener::bind(bind_addr).await.map_err(Error::from))
+        let (listener, listen_addr) = track!(listen(inherited_listener, bind_addr).await)
             .expect("failed to start listening on the given proxy address");
 
-        let proxy = ProxyServer::new(listener.incoming(), tcp_server_addr)
+        let mut proxy = ProxyServer::with_config(listener.incoming(), tcp_server_addr, config)
             .await
             .unwrap_or_else(|e| panic!("{}", e));
+        if let Some(n) = max_accept_per_poll {
+            proxy.set_max_accept_per_poll(n as usize);
+        }
+        if rebind_on_close {
+            proxy.set_rebind_on_close(listen_addr);
+        }
+        if let Some(n) = max_connections {
+            proxy.set_max_connections(n as usize);
+        }
+        if let Some(secs) = run_for {
+            proxy.shutdown_after(Duration::from_secs(secs));
+        }
+        if let Some(secs) = shutdown_grace_period {
+            proxy.set_shutdown_grace_period(Duration::from_secs(secs));
+        }
+        #[cfg(unix)]
+        watch_signals(signals, proxy.shutdown_handle(), proxy.pause_handle());
         proxy.await.unwrap_or_else(|e| panic!("{}", e));
     });
     Ok(())
 }
+
+/// Returns the inherited listener (or a new one bound to `bind_addr`) and its local address.
+///
+/// The address of an inherited listener is taken from the socket, since `bind_addr` is not
+/// given then.
+async fn listen(
+    inherited: Option<TcpListener>,
+    bind_addr: SocketAddr,
+) -> Result<(TcpListener, SocketAddr), Error> {
+    let listener = match inherited {
+        Some(listener) => listener,
+        None => track!(TcpListener::bind(bind_addr).await.map_err(Error::from); bind_addr)?,
+    };
+    let addr = track!(listener.local_addr().map_err(Error::from))?;
+    Ok((listener, addr))
+}
+
+async fn probe(bind_addr: SocketAddr, real_server_addrs: &[SocketAddr]) -> Result<(), Error> {
+    let listener = track!(TcpListener::bind(bind_addr).await.map_err(Error::from); bind_addr)?;
+    drop(listener);
+    log::info!("Bind address {} is available", bind_addr);
+
+    for &real_server_addr in real_server_addrs {
+        let connect = TcpStream::connect(real_server_addr);
+        track!(async_std::io::timeout(PROBE_TIMEOUT, connect)
+            .await
+            .map_err(|e| ErrorKind::BackendUnavailable.cause(e)); real_server_addr)?;
+        log::info!("Real server {} is reachable", real_server_addr);
+    }
+    Ok(())
+}
+
+/// Blocks SIGINT, SIGTERM and SIGUSR2 in the calling thread so that `watch_signals` can wait
+/// for them.
+#[cfg(unix)]
+fn block_signals() -> libc::sigset_t {
+    unsafe {
+        let mut set = std::mem::zeroed();
+        libc::sigemptyset(&mut set);
+        libc::sigaddset(&mut set, libc::SIGINT);
+        libc::sigaddset(&mut set, libc::SIGTERM);
+        libc::sigaddset(&mut set, libc::SIGUSR2);
+        libc::pthread_sigmask(libc::SIG_BLOCK, &set, std::ptr::null_mut());
+        set
+    }
+}
+
+/// Toggles accepting connections on SIGUSR2, and shuts down the server gracefully on the first
+/// SIGINT/SIGTERM (exits on the second one).
+#[cfg(unix)]
+fn watch_signals(set: libc::sigset_t, shutdown: wstcp::ShutdownHandle, pause: wstcp::PauseHandle) {
+    std::thread::spawn(move || {
+        let mut shutting_down = false;
+        loop {
+            let mut signal = 0;
+            unsafe { libc::sigwait(&set, &mut signal) };
+            if signal == libc::SIGUSR2 {
+                if pause.is_paused() {
+                    log::info!("Received SIGUSR2; resumes accepting connections");
+                    pause.resume();
+                } else {
+                    log::info!("Received SIGUSR2; pauses accepting connections");
+                    pause.pause();
+                }
+            } else if !shutting_down {
+                log::info!("Received signal {}; shuts down gracefully", signal);
+                shutdown.shutdown();
+                shutting_down = true;
+            } else {
+                log::warn!("Received signal {} again; exits immediately", signal);
+                std::process::exit(1);
+            }
+        }
+    });
+}
+
+/// Makes a `TcpListener` from an inherited file descriptor, which must be a listening TCP socket.
+#[cfg(unix)]
+fn listener_from_fd(fd: RawFd) -> Result<TcpListener, Error> {
+    // The descriptor is owned by this process from now on
+    let listener = unsafe { std::net::TcpListener::from_raw_fd(fd) };
+    let socket = socket2::SockRef::from(&listener);
+    let socket_type = track!(socket.r#type().map_err(Error::from); fd)?;
+    track_assert_eq!(socket_type, socket2::Type::STREAM, ErrorKind::InvalidInput; fd);
+    #[cfg(target_os = "linux")]
+    {
+        let listening = track!(socket.is_listener().map_err(Error::from); fd)?;
+        track_assert!(listening, ErrorKind::InvalidInput; fd);
+    }
+    track!(listener.set_nonblocking(true).map_err(Error::from); fd)?;
+    Ok(TcpListener::from(listener))
+}
+
+fn init_logger(log_level: LogLevelArg, log_format: LogFormatArg) {
+    let mut builder = env_logger::Builder::new();
+    builder.filter_level(log_level.into()).parse_default_env();
+    if log_format == LogFormatArg::Clf {
+        // Access log lines are written as is so that CLF tools can consume them
+        builder
+            .filter_module(ACCESS_LOG_TARGET, log::LevelFilter::Info)
+            .format(|buf, record| {
+                if record.target() == ACCESS_LOG_TARGET {
+                    writeln!(buf, "{}", record.args())
+                } else {
+                    writeln!(
+                        buf,
+                        "[{} {} {}] {}",
+                        buf.timestamp(),
+                        record.level(),
+                        record.target(),
+                        record.args()
+                    )
+                }
+            });
+    }
+    builder.init();
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn probe_works() {
+        async_std::task::block_on(async {
+            let any_addr = "127.0.0.1:0".parse().unwrap();
+            let backend = TcpListener::bind(any_addr).await.unwrap();
+            let backend_addr = backend.local_addr().unwrap();
+            assert!(probe(any_addr, &[backend_addr]).await.is_ok());
+
+            // Bind address in use
+            let in_use_addr = backend_addr;
+            let e = probe(in_use_addr, &[backend_addr]).await.unwrap_err();
+            assert_eq!(*e.kind(), ErrorKind::Io);
+
+            // Unreachable real server
+            drop(backend);
+            let e = probe(any_addr, &[backend_addr]).await.unwrap_err();
+            assert_eq!(*e.kind(), ErrorKind::BackendUnavailable);
+        });
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn listener_from_fd_works() {
+        use std::os::unix::io::IntoRawFd;
+
+        async_std::task::block_on(async {
+            let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+            let addr = listener.local_addr().unwrap();
+            let listener = listener_from_fd(listener.into_raw_fd()).unwrap();
+            let _client = TcpStream::connect(addr).await.unwrap();
+            let (_, peer) = listener.accept().await.unwrap();
+            assert_eq!(peer.ip(), addr.ip());
+
+            // The address of the inherited socket is used instead of the default bind address
+            let inherited = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+            let inherited_addr = inherited.local_addr().unwrap();
+            let inherited = listener_from_fd(inherited.into_raw_fd()).unwrap();
+            let default_addr = "0.0.0.0:13892".parse().unwrap();
+            let (_, listen_addr) = listen(Some(inherited), default_addr).await.unwrap();
+            assert_eq!(listen_addr, inherited_addr);
+
+            // Not a listening socket
+            #[cfg(target_os = "linux")]
+            {
+                let socket = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+                assert!(listener_from_fd(socket.into_raw_fd()).is_err());
+                let stream = std::net::TcpStream::connect(addr).unwrap();
+                assert!(listener_from_fd(stream.into_raw_fd()).is_err());
+            }
+        });
+    }
+}
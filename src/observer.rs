@@ -0,0 +1,43 @@
+use httpcodec::Request;
+use std::fmt;
+use std::net::SocketAddr;
+
+/// Decision on a WebSocket handshake request (see `ChannelObserver::on_handshake`).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Decision {
+    /// The request is accepted.
+    #[default]
+    Allow,
+
+    /// The request is rejected with the given status code.
+    ///
+    /// Codes other than 4xx and 5xx are replaced with `500`.
+    Reject(u16),
+
+    /// The request is accepted and relayed to the given real server.
+    Route(SocketAddr),
+}
+
+/// Observer of the lifecycle of proxy channels (see `ChannelConfig::observer`).
+///
+/// All methods do nothing by default.
+pub trait ChannelObserver: Send + Sync {
+    /// Called when a valid WebSocket handshake request is received.
+    fn on_handshake(&self, _request: &Request<()>) -> Decision {
+        Decision::Allow
+    }
+
+    /// Called when a handshake has succeeded.
+    fn on_open(&self, _peer: Option<SocketAddr>) {}
+
+    /// Called when a channel whose handshake succeeded terminates.
+    ///
+    /// `code` is that of the Close frame sent or echoed by the proxy (`1005` if the frame had no
+    /// code, or `1006` if no Close frame was sent).
+    fn on_close(&self, _code: u16) {}
+}
+impl fmt::Debug for dyn ChannelObserver {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "ChannelObserver {{ .. }}")
+    }
+}
@@ -1,4 +1,6 @@
+/// Opcode of a WebSocket frame.
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[allow(missing_docs)]
 pub enum Opcode {
     ContinuationFrame = 0x0,
     TextFrame = 0x1,
@@ -8,6 +10,7 @@ pub enum Opcode {
     Pong = 0xA,
 }
 impl Opcode {
+    /// Converts the 4-bit opcode field of a frame header to `Opcode`.
     pub fn from_u8(n: u8) -> bytecodec::Result<Self> {
         Ok(match n {
             0x0 => Opcode::ContinuationFrame,
@@ -20,6 +23,7 @@ impl Opcode {
         })
     }
 
+    /// Returns `true` if this is the opcode of a control frame (Close, Ping or Pong).
     pub fn is_control(&self) -> bool {
         matches!(self, Opcode::ConnectionClose | Opcode::Ping | Opcode::Pong)
     }
@@ -0,0 +1,80 @@
+use async_std::channel::{self, Receiver, Sender};
+use async_std::stream::Stream;
+use std::collections::HashMap;
+use std::fmt::Debug;
+use std::hash::Hash;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::task::{Context, Poll};
+
+type Entries<K> = HashMap<K, (u64, Sender<()>)>;
+
+/// Registry of active proxy channels keyed by `K`.
+///
+/// A registered channel holds a `Registration`, which is signaled when the entry is removed by
+/// `Registry::signal` or replaced by a newer registration with the same key.
+#[derive(Debug, Clone, Default)]
+pub struct Registry<K> {
+    entries: Arc<Mutex<Entries<K>>>,
+    next_id: Arc<AtomicU64>,
+}
+impl<K: Clone + Eq + Hash + Debug> Registry<K> {
+    /// Registers a new entry for `key`, signaling the entry that holds `key` (if any).
+    pub fn register(&self, key: K) -> Registration<K> {
+        let id = self.next_id.fetch_add(1, Ordering::SeqCst);
+        let (tx, rx) = channel::bounded(1);
+
+        // Dropping the old sender is the signal to the replaced registration
+        self.lock().insert(key.clone(), (id, tx));
+        Registration {
+            registry: self.clone(),
+            key,
+            id,
+            signal: rx,
+        }
+    }
+
+    /// Removes the entry for `key` and signals its holder.
+    ///
+    /// Returns `false` if there is no such entry.
+    pub fn signal(&self, key: &K) -> bool {
+        self.lock().remove(key).is_some()
+    }
+
+    /// Removes all the entries and signals their holders.
+    pub fn signal_all(&self) {
+        self.lock().clear();
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Entries<K>> {
+        self.entries.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// Entry held by a proxy channel.
+#[derive(Debug)]
+pub struct Registration<K: Clone + Eq + Hash + Debug> {
+    registry: Registry<K>,
+    key: K,
+    id: u64,
+    signal: Receiver<()>,
+}
+impl<K: Clone + Eq + Hash + Debug> Registration<K> {
+    pub fn key(&self) -> &K {
+        &self.key
+    }
+
+    /// Returns `true` if the entry has been removed or replaced.
+    pub fn poll_signaled(&mut self, cx: &mut Context) -> bool {
+        matches!(Pin::new(&mut self.signal).poll_next(cx), Poll::Ready(_))
+    }
+}
+impl<K: Clone + Eq + Hash + Debug> Drop for Registration<K> {
+    fn drop(&mut self) {
+        let mut entries = self.registry.lock();
+        if entries.get(&self.key).is_some_and(|(id, _)| *id == self.id) {
+            entries.remove(&self.key);
+        }
+    }
+}
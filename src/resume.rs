@@ -0,0 +1,61 @@
+use async_std::net::TcpStream;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::Duration;
+
+type Entries<R> = HashMap<String, (u64, R)>;
+
+/// Real server connections kept alive for clients that may reconnect with the same resume token.
+///
+/// A parked connection is not read while waiting, so data sent by the real server in the
+/// meantime is buffered by the OS (up to the socket receive buffer size).
+#[derive(Debug)]
+pub struct ParkedBackends<R = TcpStream> {
+    entries: Arc<Mutex<Entries<R>>>,
+    next_id: Arc<AtomicU64>,
+}
+impl<R: Send + 'static> ParkedBackends<R> {
+    /// Keeps `stream` for `window`; after that, the connection is closed unless taken by `take`.
+    ///
+    /// A connection already parked with `token` is closed.
+    pub fn park(&self, token: String, stream: R, window: Duration) {
+        let id = self.next_id.fetch_add(1, Ordering::SeqCst);
+        self.lock().insert(token.clone(), (id, stream));
+
+        let this = self.clone();
+        async_std::task::spawn(async move {
+            async_std::task::sleep(window).await;
+            let mut entries = this.lock();
+            if entries.get(&token).is_some_and(|(i, _)| *i == id) {
+                log::info!("Resume window has expired; closes the parked real server connection");
+                entries.remove(&token);
+            }
+        });
+    }
+
+    /// Takes the connection parked with `token`.
+    pub fn take(&self, token: &str) -> Option<R> {
+        self.lock().remove(token).map(|(_, stream)| stream)
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Entries<R>> {
+        self.entries.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+impl<R> Clone for ParkedBackends<R> {
+    fn clone(&self) -> Self {
+        ParkedBackends {
+            entries: self.entries.clone(),
+            next_id: self.next_id.clone(),
+        }
+    }
+}
+impl<R> Default for ParkedBackends<R> {
+    fn default() -> Self {
+        ParkedBackends {
+            entries: Arc::default(),
+            next_id: Arc::default(),
+        }
+    }
+}
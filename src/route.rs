@@ -0,0 +1,102 @@
+use crate::{Error, ErrorKind, Result};
+use std::fmt;
+use std::net::SocketAddr;
+use std::str::FromStr;
+use trackable::error::ErrorKindExt;
+
+/// Route from a prefix of request targets to a real server (e.g., `/db=127.0.0.1:5432`).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Route {
+    prefix: String,
+    addr: SocketAddr,
+}
+impl Route {
+    /// Makes a new `Route` instance.
+    pub fn new(prefix: &str, addr: SocketAddr) -> Self {
+        Route {
+            prefix: prefix.trim_end_matches('/').to_owned(),
+            addr,
+        }
+    }
+
+    /// Returns the address of the real server.
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// Returns `true` if `target` is under the prefix.
+    ///
+    /// The prefix matches whole path segments: `/db` matches `/db`, `/db/1` and `/db?x` but not
+    /// `/dbx`. The prefix `/` matches any target.
+    pub fn matches(&self, target: &str) -> bool {
+        target
+            .strip_prefix(&self.prefix)
+            .is_some_and(|rest| rest.is_empty() || rest.starts_with(['/', '?']))
+    }
+}
+impl FromStr for Route {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (prefix, addr) = track_assert_some!(s.split_once('='), ErrorKind::InvalidInput; s);
+        track_assert!(prefix.starts_with('/'), ErrorKind::InvalidInput; s);
+        let addr = track!(addr.parse().map_err(|e| ErrorKind::InvalidInput.cause(e)); s)?;
+        Ok(Route::new(prefix, addr))
+    }
+}
+impl fmt::Display for Route {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let prefix = if self.prefix.is_empty() {
+            "/"
+        } else {
+            &self.prefix
+        };
+        write!(f, "{}={}", prefix, self.addr)
+    }
+}
+
+/// Returns the real server of the route with the longest prefix matching `target`.
+pub fn select(routes: &[Route], target: &str) -> Option<SocketAddr> {
+    routes
+        .iter()
+        .filter(|r| r.matches(target))
+        .max_by_key(|r| r.prefix.len())
+        .map(|r| r.addr)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn route(s: &str) -> Route {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn route_works() {
+        assert!(route("/db=127.0.0.1:1").matches("/db"));
+        assert!(route("/db=127.0.0.1:1").matches("/db/1"));
+        assert!(route("/db/=127.0.0.1:1").matches("/db?x=1"));
+        assert!(!route("/db=127.0.0.1:1").matches("/dbx"));
+        assert!(route("/=127.0.0.1:1").matches("/anything"));
+        assert_eq!(route("/=127.0.0.1:1").to_string(), "/=127.0.0.1:1");
+
+        assert!("db=127.0.0.1:1".parse::<Route>().is_err());
+        assert!("/db".parse::<Route>().is_err());
+        assert!("/db=localhost".parse::<Route>().is_err());
+    }
+
+    #[test]
+    fn longest_prefix_is_selected() {
+        let routes = [
+            route("/=127.0.0.1:1"),
+            route("/db=127.0.0.1:2"),
+            route("/db/replica=127.0.0.1:3"),
+        ];
+        let port = |target| select(&routes, target).map(|a| a.port());
+        assert_eq!(port("/db/replica/1"), Some(3));
+        assert_eq!(port("/db/main"), Some(2));
+        assert_eq!(port("/cache"), Some(1));
+        assert_eq!(select(&routes[1..], "/cache"), None);
+    }
+}
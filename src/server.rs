@@ -1,18 +1,299 @@
-use crate::channel::ProxyChannel;
-use crate::{Error, Result};
-use async_std::net::Incoming;
+use crate::channel::{ChannelConfig, ProxyChannel};
+use crate::cidr;
+use crate::frame;
+use crate::observer::ChannelObserver;
+use crate::registry::Registry;
+use crate::resume::ParkedBackends;
+use crate::route::Route;
+use crate::util::Timer;
+use crate::webhook::CloseWebhook;
+use crate::{Error, ErrorKind, Result};
+use async_std::channel::{self, Receiver, Sender};
+use async_std::net::{Incoming, TcpListener, TcpStream};
 use async_std::stream::Stream;
+use httpcodec::HeaderField;
+use std::fmt;
 use std::future::Future;
+use std::io;
 use std::net::SocketAddr;
 use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
 use std::task::Context;
 use std::task::Poll;
+use std::time::Duration;
+use trackable::error::ErrorKindExt;
+
+const MIN_REBIND_BACKOFF: Duration = Duration::from_millis(100);
+const MAX_REBIND_BACKOFF: Duration = Duration::from_secs(10);
+
+type Rebind = Pin<Box<dyn Future<Output = TcpListener> + Send>>;
+type Accept = Pin<Box<dyn Future<Output = io::Result<TcpStream>> + Send>>;
+
+/// Stream of the accepted connections (the original `Incoming` or that of a rebound listener).
+struct Listener<'a> {
+    incoming: Pin<Box<dyn Stream<Item = io::Result<TcpStream>> + Send + 'a>>,
+    rebind: Option<Rebind>,
+}
+impl<'a> Listener<'a> {
+    fn new(incoming: Incoming<'a>) -> Self {
+        Listener {
+            incoming: Box::pin(incoming),
+            rebind: None,
+        }
+    }
+}
+impl<'a> fmt::Debug for Listener<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Listener {{ rebinding: {} }}", self.rebind.is_some())
+    }
+}
+
+/// `Incoming` that owns its listener.
+struct OwnedIncoming {
+    listener: Arc<TcpListener>,
+    accept: Option<Accept>,
+}
+impl Stream for OwnedIncoming {
+    type Item = io::Result<TcpStream>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+        let listener = &this.listener;
+        let accept = this.accept.get_or_insert_with(|| {
+            let listener = listener.clone();
+            Box::pin(async move { listener.accept().await.map(|(stream, _)| stream) })
+        });
+        match accept.as_mut().poll(cx) {
+            Poll::Pending => Poll::Pending,
+            Poll::Ready(result) => {
+                this.accept = None;
+                Poll::Ready(Some(result))
+            }
+        }
+    }
+}
+
+/// Handle for shutting down a `ProxyServer` gracefully from another task or thread.
+#[derive(Debug, Clone)]
+pub struct ShutdownHandle {
+    tx: Sender<()>,
+}
+impl ShutdownHandle {
+    /// Makes the server shut down gracefully (see `ProxyServer::shutdown_after`).
+    pub fn shutdown(&self) {
+        let _ = self.tx.try_send(());
+    }
+}
+
+/// Counted proxy channel (the count is decremented and the server is notified on drop).
+struct CountedChannel {
+    count: Arc<AtomicUsize>,
+    released: Sender<()>,
+}
+impl CountedChannel {
+    fn new(count: &Arc<AtomicUsize>, released: &Sender<()>) -> Self {
+        count.fetch_add(1, Ordering::SeqCst);
+        CountedChannel {
+            count: count.clone(),
+            released: released.clone(),
+        }
+    }
+}
+impl Drop for CountedChannel {
+    fn drop(&mut self) {
+        self.count.fetch_sub(1, Ordering::SeqCst);
+        let _ = self.released.try_send(());
+    }
+}
+
+/// Handle for pausing and resuming accepting connections from another task or thread.
+#[derive(Debug, Clone)]
+pub struct PauseHandle {
+    paused: Arc<AtomicBool>,
+    tx: Sender<()>,
+}
+impl PauseHandle {
+    /// Makes the server stop accepting connections (the active ones keep running).
+    ///
+    /// New connections wait in the listen backlog while paused.
+    pub fn pause(&self) {
+        self.set_paused(true);
+    }
+
+    /// Makes the server accept connections again.
+    pub fn resume(&self) {
+        self.set_paused(false);
+    }
+
+    /// Returns `true` if the server is paused.
+    pub fn is_paused(&self) -> bool {
+        self.paused.load(Ordering::SeqCst)
+    }
+
+    fn set_paused(&self, paused: bool) {
+        self.paused.store(paused, Ordering::SeqCst);
+        let _ = self.tx.try_send(());
+    }
+}
+
+/// Builder of `ProxyServer`.
+///
+/// The `ChannelConfig` carried by the builder is cloned into each proxy channel.
+///
+/// # Examples
+///
+/// ```
+/// use async_std::net::TcpListener;
+/// use std::time::Duration;
+/// use wstcp::ProxyServerBuilder;
+///
+/// # async_std::task::block_on(async {
+/// let listener = TcpListener::bind("127.0.0.1:0").await?;
+/// let proxy = ProxyServerBuilder::new("127.0.0.1:8080".parse()?)
+///     .idle_timeout(Duration::from_secs(60))
+///     .max_connections(100)
+///     .build(listener.incoming())
+///     .await?;
+/// # drop(proxy);
+/// # Ok::<(), Box<dyn std::error::Error>>(())
+/// # }).unwrap();
+/// ```
+#[derive(Debug, Clone)]
+pub struct ProxyServerBuilder {
+    real_server_addr: SocketAddr,
+    config: ChannelConfig,
+    max_accept_per_poll: Option<usize>,
+    max_connections: Option<usize>,
+    rebind_addr: Option<SocketAddr>,
+    shutdown_grace_period: Option<Duration>,
+}
+impl ProxyServerBuilder {
+    /// Makes a new `ProxyServerBuilder` instance with the default options.
+    pub fn new(real_server_addr: SocketAddr) -> Self {
+        ProxyServerBuilder {
+            real_server_addr,
+            config: ChannelConfig::default(),
+            max_accept_per_poll: None,
+            max_connections: None,
+            rebind_addr: None,
+            shutdown_grace_period: None,
+        }
+    }
+
+    /// Replaces the options applied to each proxy channel.
+    pub fn config(mut self, config: ChannelConfig) -> Self {
+        self.config = config;
+        self
+    }
+
+    /// Sets `ChannelConfig::idle_timeout`.
+    pub fn idle_timeout(mut self, timeout: Duration) -> Self {
+        self.config.idle_timeout = Some(timeout);
+        self
+    }
+
+    /// Sets `ChannelConfig::keepalive_interval`.
+    pub fn keepalive(mut self, interval: Duration) -> Self {
+        self.config.keepalive_interval = Some(interval);
+        self
+    }
+
+    /// Adds an entry to `ChannelConfig::allowed_origins`.
+    pub fn allow_origin(mut self, origin: &str) -> Self {
+        self.config.allowed_origins.push(origin.to_owned());
+        self
+    }
+
+    /// Adds an entry to `ChannelConfig::subprotocols`.
+    pub fn subprotocol(mut self, name: &str) -> Self {
+        self.config.subprotocols.push(name.to_owned());
+        self
+    }
+
+    /// Sets `ChannelConfig::observer`.
+    pub fn observer(mut self, observer: Arc<dyn ChannelObserver>) -> Self {
+        self.config.observer = Some(observer);
+        self
+    }
+
+    /// Adds an entry to `ChannelConfig::routes`.
+    pub fn route(mut self, route: Route) -> Self {
+        self.config.routes.push(route);
+        self
+    }
+
+    /// See `ProxyServer::set_max_accept_per_poll`.
+    pub fn max_accept_per_poll(mut self, n: usize) -> Self {
+        self.max_accept_per_poll = Some(n);
+        self
+    }
+
+    /// See `ProxyServer::set_max_connections`.
+    pub fn max_connections(mut self, n: usize) -> Self {
+        self.max_connections = Some(n);
+        self
+    }
+
+    /// See `ProxyServer::set_rebind_on_close`.
+    pub fn rebind_on_close(mut self, addr: SocketAddr) -> Self {
+        self.rebind_addr = Some(addr);
+        self
+    }
+
+    /// See `ProxyServer::set_shutdown_grace_period`.
+    pub fn shutdown_grace_period(mut self, period: Duration) -> Self {
+        self.shutdown_grace_period = Some(period);
+        self
+    }
+
+    /// Builds a `ProxyServer` instance serving the connections from `incoming`.
+    pub async fn build(self, incoming: Incoming<'_>) -> Result<ProxyServer<'_>> {
+        let mut server =
+            track!(ProxyServer::with_config(incoming, self.real_server_addr, self.config).await)?;
+        if let Some(n) = self.max_accept_per_poll {
+            server.set_max_accept_per_poll(n);
+        }
+        if let Some(n) = self.max_connections {
+            server.set_max_connections(n);
+        }
+        if let Some(addr) = self.rebind_addr {
+            server.set_rebind_on_close(addr);
+        }
+        if let Some(period) = self.shutdown_grace_period {
+            server.set_shutdown_grace_period(period);
+        }
+        Ok(server)
+    }
+}
 
 /// WebSocket to TCP proxy server.
 #[derive(Debug)]
 pub struct ProxyServer<'a> {
     real_server_addr: SocketAddr,
-    incoming: Incoming<'a>,
+    listener: Listener<'a>,
+    rebind_addr: Option<SocketAddr>,
+    config: ChannelConfig,
+    sessions: Registry<String>,
+    close_webhook: Option<CloseWebhook>,
+    connections: Registry<u64>,
+    parked: ParkedBackends,
+    next_conn_id: u64,
+    max_accept_per_poll: usize,
+    max_connections: Option<usize>,
+    live_channels: Arc<AtomicUsize>,
+    rejected_channels: Arc<AtomicUsize>,
+    released_tx: Sender<()>,
+    released_rx: Receiver<()>,
+    shutdown_timer: Option<Timer>,
+    shutdown_tx: Sender<()>,
+    shutdown_rx: Receiver<()>,
+    shutdown_grace_period: Option<Duration>,
+    grace_timer: Option<Timer>,
+    pause: PauseHandle,
+    pause_rx: Receiver<()>,
+    alive_tx: Option<Sender<()>>,
+    alive_rx: Receiver<()>,
 }
 impl<'a> ProxyServer<'a> {
     /// Makes a new `ProxyServer` instance.
@@ -20,26 +301,225 @@ impl<'a> ProxyServer<'a> {
         incoming: Incoming<'a>,
         real_server_addr: SocketAddr,
     ) -> Result<ProxyServer<'a>> {
+        Self::with_config(incoming, real_server_addr, ChannelConfig::default()).await
+    }
+
+    /// Returns a builder of a `ProxyServer` instance relaying to `real_server_addr`.
+    pub fn builder(real_server_addr: SocketAddr) -> ProxyServerBuilder {
+        ProxyServerBuilder::new(real_server_addr)
+    }
+
+    /// Makes a new `ProxyServer` instance that applies `config` to each proxy channel.
+    pub async fn with_config(
+        incoming: Incoming<'a>,
+        real_server_addr: SocketAddr,
+        config: ChannelConfig,
+    ) -> Result<ProxyServer<'a>> {
+        for (name, value) in &config.extra_response_headers {
+            track!(HeaderField::new(name, value).map_err(|e| ErrorKind::InvalidInput.cause(e)); name, value)?;
+        }
+        track_assert_ne!(config.buffer_size, Some(0), ErrorKind::InvalidInput);
+        if let Some(code) = config.session_close_code {
+            track_assert!(frame::is_valid_close_code(code), ErrorKind::InvalidInput; code);
+        }
+        let payloads = config
+            .client_heartbeat
+            .iter()
+            .map(|(payload, _)| payload)
+            .chain(&config.overflow_notice);
+        for payload in payloads {
+            track_assert!(payload.len() <= config.buffer_size(), ErrorKind::InvalidInput;
+                          payload.len(), config.buffer_size());
+        }
         log::info!("Starts a WebSocket proxy server");
+        let close_webhook = match &config.close_webhook {
+            Some(url) => Some(track!(CloseWebhook::new(url))?),
+            None => None,
+        };
+        let (alive_tx, alive_rx) = channel::bounded(1);
+        let (shutdown_tx, shutdown_rx) = channel::bounded(1);
+        let (pause_tx, pause_rx) = channel::bounded(1);
+        let (released_tx, released_rx) = channel::bounded(1);
         Ok(ProxyServer {
             real_server_addr,
-            incoming,
+            listener: Listener::new(incoming),
+            rebind_addr: None,
+            config,
+            sessions: Registry::default(),
+            close_webhook,
+            connections: Registry::default(),
+            parked: ParkedBackends::default(),
+            next_conn_id: 0,
+            max_accept_per_poll: usize::MAX,
+            max_connections: None,
+            live_channels: Arc::default(),
+            rejected_channels: Arc::default(),
+            released_tx,
+            released_rx,
+            shutdown_timer: None,
+            shutdown_tx,
+            shutdown_rx,
+            shutdown_grace_period: None,
+            grace_timer: None,
+            pause: PauseHandle {
+                paused: Arc::default(),
+                tx: pause_tx,
+            },
+            pause_rx,
+            alive_tx: Some(alive_tx),
+            alive_rx,
         })
     }
+
+    /// Makes the server shut down gracefully after `duration`.
+    ///
+    /// On shutdown, the server stops accepting connections and starts closing the active ones.
+    /// The server future resolves once all of them have terminated.
+    pub fn shutdown_after(&mut self, duration: Duration) {
+        self.shutdown_timer = Some(Timer::new(duration));
+    }
+
+    /// Returns a handle for shutting down the server gracefully.
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        ShutdownHandle {
+            tx: self.shutdown_tx.clone(),
+        }
+    }
+
+    /// Stops accepting connections until `resume` is called (see `PauseHandle::pause`).
+    pub fn pause(&self) {
+        self.pause.pause();
+    }
+
+    /// Starts accepting connections again.
+    pub fn resume(&self) {
+        self.pause.resume();
+    }
+
+    /// Returns a handle for pausing and resuming the server while it is running.
+    pub fn pause_handle(&self) -> PauseHandle {
+        self.pause.clone()
+    }
+
+    /// Limits how long the server waits for the active connections to terminate on shutdown.
+    ///
+    /// Once the period has elapsed, the server future resolves even if some of them are still
+    /// active.
+    pub fn set_shutdown_grace_period(&mut self, period: Duration) {
+        self.shutdown_grace_period = Some(period);
+    }
+
+    /// Limits the number of connections accepted per wakeup of the server.
+    ///
+    /// Once the limit is reached, the server yields to the proxy channels and accepts the rest
+    /// of the backlog in the next wakeup.
+    pub fn set_max_accept_per_poll(&mut self, n: usize) {
+        self.max_accept_per_poll = std::cmp::max(n, 1);
+    }
+
+    /// Limits the number of concurrent proxy channels.
+    ///
+    /// Handshake requests received while the limit is reached are answered with
+    /// `503 Service Unavailable`. At most `n` such connections are kept at a time; beyond that,
+    /// the server stops accepting, leaving new connections in the listen backlog until a channel
+    /// terminates.
+    pub fn set_max_connections(&mut self, n: usize) {
+        self.max_connections = Some(n);
+    }
+
+    /// Makes the server rebind `addr` (retrying with backoff) when the listening socket is
+    /// closed unexpectedly, instead of terminating.
+    pub fn set_rebind_on_close(&mut self, addr: SocketAddr) {
+        self.rebind_addr = Some(addr);
+    }
+
+    /// Starts closing the connection identified by `conn_id` gracefully.
+    ///
+    /// Connection ids are assigned in accepting order (starting from 0) and logged when
+    /// the proxy channels are created.
+    ///
+    /// Returns `false` if there is no such active connection.
+    pub fn close_connection(&self, conn_id: u64) -> bool {
+        self.connections.signal(&conn_id)
+    }
 }
 impl<'a> Future for ProxyServer<'a> {
     type Output = Result<()>;
 
     fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
         let this = self.get_mut();
-        loop {
-            match Pin::new(&mut this.incoming).poll_next(cx) {
+        let requested = matches!(
+            Pin::new(&mut this.shutdown_rx).poll_next(cx),
+            Poll::Ready(Some(()))
+        );
+        if this
+            .shutdown_timer
+            .as_mut()
+            .is_some_and(|t| t.poll_expired(cx))
+            || (requested && this.alive_tx.is_some())
+        {
+            log::info!("Shuts down the WebSocket proxy server");
+            this.shutdown_timer = None;
+            this.alive_tx = None;
+            this.connections.signal_all();
+            this.grace_timer = this.shutdown_grace_period.map(Timer::new);
+        }
+        if this.alive_tx.is_none() {
+            if this
+                .grace_timer
+                .as_mut()
+                .is_some_and(|t| t.poll_expired(cx))
+            {
+                log::warn!("Shutdown grace period has expired; drops the active connections");
+                return Poll::Ready(Ok(()));
+            }
+
+            // Waits until all the proxy channels have terminated (i.e., dropped their senders)
+            return match Pin::new(&mut this.alive_rx).poll_next(cx) {
+                Poll::Pending => Poll::Pending,
+                Poll::Ready(_) => Poll::Ready(Ok(())),
+            };
+        }
+        // Polls the notifications only to be woken up on `PauseHandle::resume`
+        while let Poll::Ready(Some(())) = Pin::new(&mut this.pause_rx).poll_next(cx) {}
+        if this.pause.is_paused() {
+            return Poll::Pending;
+        }
+        if let Some(rebind) = this.listener.rebind.as_mut() {
+            match rebind.as_mut().poll(cx) {
+                Poll::Pending => return Poll::Pending,
+                Poll::Ready(listener) => {
+                    log::info!("Rebound the TCP socket for the WebSocket proxy server");
+                    this.listener.incoming = Box::pin(OwnedIncoming {
+                        listener: Arc::new(listener),
+                        accept: None,
+                    });
+                    this.listener.rebind = None;
+                }
+            }
+        }
+        // Polls the notifications only to be woken up when a channel releases its slot
+        while let Poll::Ready(Some(())) = Pin::new(&mut this.released_rx).poll_next(cx) {}
+        for _ in 0..this.max_accept_per_poll {
+            if this.max_connections.is_some_and(|n| {
+                this.live_channels.load(Ordering::SeqCst) >= n
+                    && this.rejected_channels.load(Ordering::SeqCst) >= n
+            }) {
+                return Poll::Pending;
+            }
+            match this.listener.incoming.as_mut().poll_next(cx) {
                 Poll::Pending => {
-                    break;
+                    return Poll::Pending;
                 }
                 Poll::Ready(None) => {
+                    // Shutting down never ends the stream, so the socket has been closed unexpectedly
                     log::warn!("TCP socket for the WebSocket proxy server has been closed");
-                    return Poll::Ready(Ok(()));
+                    let Some(addr) = this.rebind_addr else {
+                        return Poll::Ready(Ok(()));
+                    };
+                    this.listener.rebind = Some(Box::pin(rebind(addr)));
+                    cx.waker().wake_by_ref();
+                    return Poll::Pending;
                 }
                 Poll::Ready(Some(Err(e))) => {
                     return Poll::Ready(Err(track!(Error::from(e))));
@@ -47,21 +527,399 @@ impl<'a> Future for ProxyServer<'a> {
                 Poll::Ready(Some(Ok(stream))) => {
                     let addr = stream.peer_addr()?;
                     log::debug!("New client arrived: {:?}", addr);
+                    if !cidr::is_allowed(
+                        addr.ip(),
+                        &this.config.allow_cidrs,
+                        &this.config.deny_cidrs,
+                    ) {
+                        log::info!("Refuses a client by the CIDR rules: {}", addr);
+                        continue;
+                    }
 
-                    let channel = ProxyChannel::new(stream, this.real_server_addr);
+                    let log_level = this.config.lifecycle_log_level(this.next_conn_id);
+                    let connection = this.connections.register(this.next_conn_id);
+                    this.next_conn_id += 1;
+                    let mut channel = ProxyChannel::new(
+                        stream,
+                        this.real_server_addr,
+                        this.config.clone(),
+                        this.sessions.clone(),
+                        this.close_webhook.clone(),
+                        connection,
+                        this.parked.clone(),
+                    );
+                    let live_channels = this.live_channels.load(Ordering::SeqCst);
+                    let counted = if this.max_connections.is_some_and(|n| live_channels >= n) {
+                        // Rejected channels are counted separately so that idle clients cannot
+                        // pile up without limit while waiting for the 503 response
+                        channel.reject_over_capacity();
+                        CountedChannel::new(&this.rejected_channels, &this.released_tx)
+                    } else {
+                        CountedChannel::new(&this.live_channels, &this.released_tx)
+                    };
+                    let alive = this.alive_tx.clone();
                     async_std::task::spawn(async move {
+                        let _alive = alive;
+                        let _counted = counted;
                         match channel.await {
                             Err(e) => {
                                 log::warn!("A proxy channel aborted: {}", e);
                             }
                             Ok(()) => {
-                                log::info!("A proxy channel terminated normally");
+                                log::log!(log_level, "A proxy channel terminated normally");
                             }
                         }
                     });
                 }
             }
         }
+
+        // Yields so that the rest of the backlog is accepted in the next poll
+        cx.waker().wake_by_ref();
         Poll::Pending
     }
 }
+
+async fn rebind(addr: SocketAddr) -> TcpListener {
+    let mut backoff = MIN_REBIND_BACKOFF;
+    loop {
+        match TcpListener::bind(addr).await {
+            Ok(listener) => return listener,
+            Err(e) => {
+                log::warn!("Cannot rebind {}: {} (retries in {:?})", addr, e, backoff);
+                async_std::task::sleep(backoff).await;
+                backoff = std::cmp::min(backoff * 2, MAX_REBIND_BACKOFF);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::testing::{capture_logs, captured_logs, read_response, HANDSHAKE_REQUEST};
+    use async_std::io::{ReadExt, WriteExt};
+    use async_std::net::{TcpListener, TcpStream};
+    use std::task::Waker;
+    use std::time::Instant;
+
+    #[test]
+    fn invalid_extra_response_header_is_rejected() {
+        async_std::task::block_on(async {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let config = ChannelConfig {
+                extra_response_headers: vec![("Bad Name".to_owned(), "x".to_owned())],
+                ..Default::default()
+            };
+            let backend_addr = "127.0.0.1:1".parse().unwrap();
+            let result = ProxyServer::with_config(listener.incoming(), backend_addr, config).await;
+            assert_eq!(
+                result.err().map(|e| *e.kind()),
+                Some(ErrorKind::InvalidInput)
+            );
+        });
+    }
+
+    #[test]
+    fn invalid_session_close_code_is_rejected() {
+        async_std::task::block_on(async {
+            let backend_addr = "127.0.0.1:1".parse().unwrap();
+            for (code, valid) in [(4000, true), (1000, true), (0, false), (1006, false)] {
+                let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+                let config = ChannelConfig {
+                    session_close_code: Some(code),
+                    ..Default::default()
+                };
+                let result =
+                    ProxyServer::with_config(listener.incoming(), backend_addr, config).await;
+                assert_eq!(result.is_ok(), valid, "{}", code);
+            }
+        });
+    }
+
+    #[test]
+    fn shutdown_after_works() {
+        async_std::task::block_on(async {
+            let backend = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let backend_addr = backend.local_addr().unwrap();
+            async_std::task::spawn(async move {
+                let (mut stream, _) = backend.accept().await.unwrap();
+                let _ = stream.read_to_end(&mut Vec::new()).await;
+            });
+
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let proxy_addr = listener.local_addr().unwrap();
+            let client = async_std::task::spawn(async move {
+                let mut client = TcpStream::connect(proxy_addr).await.unwrap();
+                client.write_all(HANDSHAKE_REQUEST).await.unwrap();
+                let response = read_response(&mut client).await;
+                assert!(response.starts_with(b"HTTP/1.1 101 "));
+
+                // Replies to the Close frame sent on shutdown
+                let mut close = vec![0; 4 + "connection closed by server".len()];
+                client.read_exact(&mut close).await.unwrap();
+                let reply = [0x88, 0x82, 0, 0, 0, 0, 0x03, 0xE9];
+                client.write_all(&reply).await.unwrap();
+                client.read_to_end(&mut Vec::new()).await.unwrap();
+                close
+            });
+
+            let started_at = Instant::now();
+            let mut proxy = ProxyServer::new(listener.incoming(), backend_addr)
+                .await
+                .unwrap();
+            proxy.shutdown_after(Duration::from_millis(200));
+            proxy.await.unwrap();
+            assert!(started_at.elapsed() >= Duration::from_millis(200));
+
+            let close = client.await;
+            assert_eq!(close[..4], [0x88, 0x1D, 0x03, 0xE9]);
+        });
+    }
+
+    #[test]
+    fn shutdown_handle_works() {
+        async_std::task::block_on(async {
+            let backend = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let backend_addr = backend.local_addr().unwrap();
+            async_std::task::spawn(async move {
+                let (_stream, _) = backend.accept().await.unwrap();
+                async_std::task::sleep(Duration::from_secs(10)).await;
+            });
+
+            // The client never replies to the Close frame sent on shutdown
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let proxy_addr = listener.local_addr().unwrap();
+            let mut proxy = ProxyServer::new(listener.incoming(), backend_addr)
+                .await
+                .unwrap();
+            proxy.set_shutdown_grace_period(Duration::from_millis(200));
+            let handle = proxy.shutdown_handle();
+            async_std::task::spawn(async move {
+                let mut client = TcpStream::connect(proxy_addr).await.unwrap();
+                client.write_all(HANDSHAKE_REQUEST).await.unwrap();
+                async_std::task::sleep(Duration::from_millis(100)).await;
+                handle.shutdown();
+                async_std::task::sleep(Duration::from_secs(10)).await;
+            });
+
+            let started_at = Instant::now();
+            proxy.await.unwrap();
+            let elapsed = started_at.elapsed();
+            assert!(elapsed >= Duration::from_millis(300));
+            assert!(elapsed < Duration::from_secs(5));
+        });
+    }
+
+    #[test]
+    fn max_connections_works() {
+        async fn handshake(proxy_addr: SocketAddr) -> (TcpStream, Vec<u8>) {
+            let mut client = TcpStream::connect(proxy_addr).await.unwrap();
+            client.write_all(HANDSHAKE_REQUEST).await.unwrap();
+            let response = read_response(&mut client).await;
+            (client, response)
+        }
+
+        async_std::task::block_on(async {
+            let backend = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let backend_addr = backend.local_addr().unwrap();
+            async_std::task::spawn(async move {
+                loop {
+                    let (mut stream, _) = backend.accept().await.unwrap();
+                    async_std::task::spawn(async move {
+                        let _ = stream.read_to_end(&mut Vec::new()).await;
+                    });
+                }
+            });
+
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let proxy_addr = listener.local_addr().unwrap();
+            let mut proxy = ProxyServer::new(listener.incoming(), backend_addr)
+                .await
+                .unwrap();
+            proxy.set_max_connections(1);
+            let handle = proxy.shutdown_handle();
+            let clients = async_std::task::spawn(async move {
+                let (first, response) = handshake(proxy_addr).await;
+                assert!(response.starts_with(b"HTTP/1.1 101 "));
+                let (_, response) = handshake(proxy_addr).await;
+                assert!(response.starts_with(b"HTTP/1.1 503 "));
+                async_std::task::sleep(Duration::from_millis(100)).await;
+
+                // An idle client holds the only slot for rejected clients, so the next one is
+                // left in the backlog until the idle one leaves
+                let idle = TcpStream::connect(proxy_addr).await.unwrap();
+                async_std::task::sleep(Duration::from_millis(50)).await;
+                let timeout = Duration::from_millis(200);
+                let result = async_std::future::timeout(timeout, handshake(proxy_addr)).await;
+                assert!(result.is_err());
+                drop(idle);
+                let (_, response) = handshake(proxy_addr).await;
+                assert!(response.starts_with(b"HTTP/1.1 503 "));
+
+                // The slot is released once the first channel has terminated
+                drop(first);
+                async_std::task::sleep(Duration::from_millis(100)).await;
+                let (_, response) = handshake(proxy_addr).await;
+                assert!(response.starts_with(b"HTTP/1.1 101 "));
+                handle.shutdown();
+            });
+            proxy.set_shutdown_grace_period(Duration::from_millis(100));
+            proxy.await.unwrap();
+            clients.await;
+        });
+    }
+
+    #[test]
+    fn pause_works() {
+        async_std::task::block_on(async {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let proxy_addr = listener.local_addr().unwrap();
+            let backend_addr = "127.0.0.1:1".parse().unwrap();
+            let mut proxy = ProxyServer::new(listener.incoming(), backend_addr)
+                .await
+                .unwrap();
+            let mut cx = Context::from_waker(Waker::noop());
+
+            proxy.pause();
+            let _client = TcpStream::connect(proxy_addr).await.unwrap();
+            assert!(Pin::new(&mut proxy).poll(&mut cx).is_pending());
+            assert_eq!(proxy.next_conn_id, 0);
+
+            proxy.pause_handle().resume();
+            assert!(Pin::new(&mut proxy).poll(&mut cx).is_pending());
+            assert_eq!(proxy.next_conn_id, 1);
+        });
+    }
+
+    #[test]
+    fn max_accept_per_poll_works() {
+        async_std::task::block_on(async {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let proxy_addr = listener.local_addr().unwrap();
+            let backlog = (0..7)
+                .map(|_| std::net::TcpStream::connect(proxy_addr).unwrap())
+                .collect::<Vec<_>>();
+
+            let backend_addr = "127.0.0.1:1".parse().unwrap();
+            let mut proxy = ProxyServer::new(listener.incoming(), backend_addr)
+                .await
+                .unwrap();
+            proxy.set_max_accept_per_poll(3);
+            let mut cx = Context::from_waker(Waker::noop());
+            for accepted in [3, 6, 7] {
+                assert!(Pin::new(&mut proxy).poll(&mut cx).is_pending());
+                assert_eq!(proxy.next_conn_id, accepted);
+            }
+            drop(backlog);
+        });
+    }
+
+    #[test]
+    fn rebind_on_close_works() {
+        async_std::task::block_on(async {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let backend_addr = "127.0.0.1:1".parse().unwrap();
+            let mut proxy = ProxyServer::new(listener.incoming(), backend_addr)
+                .await
+                .unwrap();
+
+            // Simulates that the listening socket has been closed
+            let addr = TcpListener::bind("127.0.0.1:0")
+                .await
+                .unwrap()
+                .local_addr()
+                .unwrap();
+            proxy.listener.incoming = Box::pin(async_std::stream::empty());
+            proxy.set_rebind_on_close(addr);
+            let result = async_std::future::timeout(Duration::from_millis(200), &mut proxy).await;
+            assert!(result.is_err());
+            assert!(proxy.listener.rebind.is_none());
+
+            let _client = TcpStream::connect(addr).await.unwrap();
+            let _ = async_std::future::timeout(Duration::from_millis(200), &mut proxy).await;
+            assert_eq!(proxy.next_conn_id, 1);
+
+            // Without the option, the server terminates
+            proxy.listener.incoming = Box::pin(async_std::stream::empty());
+            proxy.rebind_addr = None;
+            assert!(proxy.await.is_ok());
+        });
+    }
+
+    #[test]
+    fn cidr_rules_work() {
+        async_std::task::block_on(async {
+            let backend_addr = "127.0.0.1:1".parse().unwrap();
+            for (allow, deny, accepted) in [
+                (vec![], vec!["127.0.0.0/8"], false),
+                (vec!["10.0.0.0/8"], vec![], false),
+                (vec!["127.0.0.1"], vec![], true),
+                (vec!["127.0.0.0/8"], vec!["127.0.0.1"], false),
+            ] {
+                let config = ChannelConfig {
+                    allow_cidrs: allow.into_iter().map(|c| c.parse().unwrap()).collect(),
+                    deny_cidrs: deny.into_iter().map(|c| c.parse().unwrap()).collect(),
+                    ..Default::default()
+                };
+                let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+                let mut client = TcpStream::connect(listener.local_addr().unwrap())
+                    .await
+                    .unwrap();
+                let mut proxy = ProxyServer::with_config(listener.incoming(), backend_addr, config)
+                    .await
+                    .unwrap();
+                let mut cx = Context::from_waker(Waker::noop());
+                assert!(Pin::new(&mut proxy).poll(&mut cx).is_pending());
+                assert_eq!(proxy.next_conn_id, u64::from(accepted));
+                if !accepted {
+                    // Refused connections are closed immediately
+                    let n = client.read(&mut [0; 1]).await.unwrap_or(0);
+                    assert_eq!(n, 0);
+                }
+            }
+        });
+    }
+
+    #[test]
+    fn log_sample_rate_works() {
+        capture_logs();
+        async_std::task::block_on(async {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let proxy_addr = listener.local_addr().unwrap();
+            let backlog = (0..10)
+                .map(|_| std::net::TcpStream::connect(proxy_addr).unwrap())
+                .collect::<Vec<_>>();
+
+            let config = ChannelConfig {
+                log_sample_rate: Some(3),
+                ..Default::default()
+            };
+            let backend_addr = "127.0.0.1:1".parse().unwrap();
+            let mut proxy = ProxyServer::with_config(listener.incoming(), backend_addr, config)
+                .await
+                .unwrap();
+            let mut cx = Context::from_waker(Waker::noop());
+            while proxy.next_conn_id < 10 {
+                assert!(Pin::new(&mut proxy).poll(&mut cx).is_pending());
+            }
+
+            // Every connection is counted ...
+            assert_eq!(proxy.next_conn_id, 10);
+
+            // ... but only one in three is logged at the info level
+            let created = captured_logs(log::Level::Info)
+                .into_iter()
+                .filter_map(|m| {
+                    m.strip_prefix("New proxy channel is created: ")
+                        .map(String::from)
+                })
+                .collect::<Vec<_>>();
+            assert_eq!(
+                created,
+                ["conn_id=0", "conn_id=3", "conn_id=6", "conn_id=9"]
+            );
+            drop(backlog);
+        });
+    }
+}
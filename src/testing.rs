@@ -0,0 +1,64 @@
+//! Helpers shared by the unit tests.
+use async_std::io::{Read, ReadExt};
+use std::cell::RefCell;
+use std::sync::Once;
+
+pub const HANDSHAKE_REQUEST: &[u8] = b"GET / HTTP/1.1\r\n\
+Host: localhost\r\n\
+Upgrade: websocket\r\n\
+Connection: Upgrade\r\n\
+Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\
+Sec-WebSocket-Version: 13\r\n\
+\r\n";
+
+/// Reads the status line and the header of an HTTP response.
+pub async fn read_response<T: Read + Unpin>(client: &mut T) -> Vec<u8> {
+    let mut response = Vec::new();
+    let mut b = [0; 1];
+    while !response.ends_with(b"\r\n\r\n") {
+        client.read_exact(&mut b).await.unwrap();
+        response.push(b[0]);
+    }
+    response
+}
+
+thread_local! {
+    static RECORDS: RefCell<Vec<(log::Level, String)>> = const { RefCell::new(Vec::new()) };
+}
+
+/// Logger that keeps the records (at the info level and above) per logging thread.
+struct CapturingLogger;
+impl log::Log for CapturingLogger {
+    fn enabled(&self, metadata: &log::Metadata) -> bool {
+        metadata.level() <= log::Level::Info
+    }
+
+    fn log(&self, record: &log::Record) {
+        if self.enabled(record.metadata()) {
+            let message = record.args().to_string();
+            RECORDS.with(|r| r.borrow_mut().push((record.level(), message)));
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+/// Starts capturing the log records of all the tests.
+pub fn capture_logs() {
+    static INIT: Once = Once::new();
+    INIT.call_once(|| {
+        log::set_logger(&CapturingLogger).unwrap();
+        log::set_max_level(log::LevelFilter::Info);
+    });
+}
+
+/// Returns the messages logged at `level` by the current thread since `capture_logs()` was called.
+pub fn captured_logs(level: log::Level) -> Vec<String> {
+    RECORDS.with(|r| {
+        r.borrow()
+            .iter()
+            .filter(|(l, _)| *l == level)
+            .map(|(_, m)| m.clone())
+            .collect()
+    })
+}
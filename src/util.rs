@@ -1,8 +1,18 @@
 use base64::{engine::general_purpose::STANDARD, Engine};
 use sha1::{Digest, Sha1};
+use std::fmt;
+use std::future::Future;
+use std::pin::Pin;
+use std::task::Context;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 const GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
 
+const WEEKDAYS: [&str; 7] = ["Thu", "Fri", "Sat", "Sun", "Mon", "Tue", "Wed"];
+const MONTHS: [&str; 12] = [
+    "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+];
+
 #[derive(Debug)]
 pub struct WebSocketKey(pub String);
 
@@ -13,6 +23,98 @@ pub fn calc_accept_hash(key: &WebSocketKey) -> String {
     STANDARD.encode(output)
 }
 
+/// One-shot timer.
+pub struct Timer(Pin<Box<dyn Future<Output = ()> + Send + 'static>>);
+impl Timer {
+    pub fn new(timeout: Duration) -> Self {
+        Timer(Box::pin(async_std::task::sleep(timeout)))
+    }
+
+    /// Returns `true` if the timer has expired, otherwise the current task will be woken up on expiry.
+    pub fn poll_expired(&mut self, cx: &mut Context) -> bool {
+        self.0.as_mut().poll(cx).is_ready()
+    }
+}
+impl fmt::Debug for Timer {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Timer {{ .. }}")
+    }
+}
+
+pub fn is_valid_header_value(value: &str) -> bool {
+    value
+        .bytes()
+        .all(|b| b == b'\t' || (b' '..=b'~').contains(&b))
+}
+
+/// Returns `true` if `s` matches `pattern`, in which `*` matches any sequence of characters.
+pub fn matches_wildcard(pattern: &str, s: &str) -> bool {
+    let mut parts = pattern.split('*');
+    let first = parts.next().unwrap_or("");
+    let mut rest = match s.strip_prefix(first) {
+        Some(rest) => rest,
+        None => return false,
+    };
+    let mut parts = parts.collect::<Vec<_>>();
+    let last = match parts.pop() {
+        Some(last) => last,
+        None => return rest.is_empty(),
+    };
+    for part in parts {
+        match rest.find(part) {
+            Some(i) => rest = &rest[i + part.len()..],
+            None => return false,
+        }
+    }
+    rest.ends_with(last)
+}
+
+/// Formats `time` as an IMF-fixdate (RFC 7231), e.g. `Sun, 06 Nov 1994 08:49:37 GMT`.
+pub fn format_http_date(time: SystemTime) -> String {
+    let secs = time.duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs());
+    let days = secs / 86400;
+    let (year, month, day) = civil_from_days(days);
+    format!(
+        "{}, {:02} {} {} {:02}:{:02}:{:02} GMT",
+        WEEKDAYS[(days % 7) as usize],
+        day,
+        MONTHS[month as usize - 1],
+        year,
+        secs % 86400 / 3600,
+        secs % 3600 / 60,
+        secs % 60
+    )
+}
+
+/// Formats `time` as a Common Log Format timestamp, e.g. `[10/Oct/2000:13:55:36 +0000]`.
+pub fn format_clf_date(time: SystemTime) -> String {
+    let secs = time.duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs());
+    let (year, month, day) = civil_from_days(secs / 86400);
+    format!(
+        "[{:02}/{}/{}:{:02}:{:02}:{:02} +0000]",
+        day,
+        MONTHS[month as usize - 1],
+        year,
+        secs % 86400 / 3600,
+        secs % 3600 / 60,
+        secs % 60
+    )
+}
+
+// See: http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+fn civil_from_days(days: u64) -> (u64, u64, u64) {
+    let z = days + 719_468;
+    let era = z / 146_097;
+    let doe = z - era * 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + u64::from(month <= 2);
+    (year, month, day)
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -22,4 +124,46 @@ mod test {
         let hash = calc_accept_hash(&WebSocketKey("dGhlIHNhbXBsZSBub25jZQ==".to_owned()));
         assert_eq!(hash, "s3pPLMBiTxaQ9kYGzzhZRbK+xOo=");
     }
+
+    #[test]
+    fn http_date_works() {
+        let time = UNIX_EPOCH + std::time::Duration::from_secs(784_111_777);
+        assert_eq!(format_http_date(time), "Sun, 06 Nov 1994 08:49:37 GMT");
+
+        let time = UNIX_EPOCH + std::time::Duration::from_secs(951_782_400);
+        assert_eq!(format_http_date(time), "Tue, 29 Feb 2000 00:00:00 GMT");
+    }
+
+    #[test]
+    fn clf_date_works() {
+        let time = UNIX_EPOCH + std::time::Duration::from_secs(971_186_136);
+        assert_eq!(format_clf_date(time), "[10/Oct/2000:13:55:36 +0000]");
+    }
+
+    #[test]
+    fn wildcard_works() {
+        assert!(matches_wildcard(
+            "https://example.com",
+            "https://example.com"
+        ));
+        assert!(!matches_wildcard(
+            "https://example.com",
+            "https://example.com.evil"
+        ));
+        assert!(matches_wildcard("*", "null"));
+        assert!(matches_wildcard(
+            "https://*.example.com",
+            "https://a.b.example.com"
+        ));
+        assert!(!matches_wildcard(
+            "https://*.example.com",
+            "https://example.com"
+        ));
+        assert!(!matches_wildcard(
+            "https://*.example.com",
+            "https://evil.com"
+        ));
+        assert!(matches_wildcard("http://*:*", "http://localhost:8080"));
+        assert!(!matches_wildcard("a*a", "a"));
+    }
 }
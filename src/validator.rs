@@ -0,0 +1,130 @@
+use std::fmt;
+
+pub(crate) const MAX_CONTROL_PAYLOAD_LEN: u64 = 125;
+
+/// Violation of a RFC 6455 rule (or a configured limit) on the frames sent by clients.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[allow(missing_docs)]
+pub enum Violation {
+    ReservedBits,
+    UnmaskedFrame,
+    FragmentedControlFrame,
+    ControlFrameTooLarge,
+    UnexpectedContinuation,
+    UnfinishedMessage,
+    InvalidUtf8,
+    InvalidCloseCode(u16),
+    MessageTooBig,
+}
+impl Violation {
+    /// Returns the close code to be sent for the violation.
+    pub fn close_code(self) -> u16 {
+        match self {
+            Violation::InvalidUtf8 => 1007,
+            Violation::MessageTooBig => 1009,
+            _ => 1002,
+        }
+    }
+}
+impl fmt::Display for Violation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Violation::ReservedBits => write!(f, "RSV bits must be 0 (RFC 6455, 5.2)"),
+            Violation::UnmaskedFrame => write!(f, "client frames must be masked (RFC 6455, 5.1)"),
+            Violation::FragmentedControlFrame => {
+                write!(f, "control frames must not be fragmented (RFC 6455, 5.5)")
+            }
+            Violation::ControlFrameTooLarge => write!(
+                f,
+                "control frame payloads must be at most 125 bytes (RFC 6455, 5.5)"
+            ),
+            Violation::UnexpectedContinuation => write!(
+                f,
+                "continuation frame without a preceding fragment (RFC 6455, 5.4)"
+            ),
+            Violation::UnfinishedMessage => write!(
+                f,
+                "data frame in the middle of a fragmented message (RFC 6455, 5.4)"
+            ),
+            Violation::InvalidUtf8 => write!(f, "text must be valid UTF-8 (RFC 6455, 8.1)"),
+            Violation::InvalidCloseCode(code) => {
+                write!(f, "invalid close code {} (RFC 6455, 7.4)", code)
+            }
+            Violation::MessageTooBig => write!(f, "message exceeds the maximum size"),
+        }
+    }
+}
+
+/// Checks the frames sent by a client against RFC 6455.
+///
+/// Reserved bits, masking, fragmentation, the size of control frames, UTF-8 of text messages and
+/// close codes are checked by `FrameDecoder` itself.
+#[derive(Debug, Default)]
+pub struct FrameValidator;
+impl FrameValidator {
+    /// Checks the reason of a Close frame.
+    pub fn check_close_reason(&self, reason: &[u8]) -> Result<(), Violation> {
+        if std::str::from_utf8(reason).is_err() {
+            return Err(Violation::InvalidUtf8);
+        }
+        Ok(())
+    }
+}
+
+/// Incremental UTF-8 checker for the payload of a text message.
+#[derive(Debug, Default)]
+pub struct Utf8Validator {
+    pending: Vec<u8>,
+}
+impl Utf8Validator {
+    /// Checks (a part of) the unmasked payload.
+    pub fn check(&mut self, data: &[u8]) -> Result<(), Violation> {
+        // A character may be split across reads and frames, so incomplete trailing bytes are kept
+        self.pending.extend_from_slice(data);
+        match std::str::from_utf8(&self.pending) {
+            Ok(_) => self.pending.clear(),
+            Err(e) if e.error_len().is_some() => return Err(Violation::InvalidUtf8),
+            Err(e) => {
+                let valid = e.valid_up_to();
+                self.pending.drain(..valid);
+            }
+        }
+        Ok(())
+    }
+
+    /// Checks the end of the message (i.e., a frame with the FIN bit set).
+    pub fn check_end(&mut self) -> Result<(), Violation> {
+        if !self.pending.is_empty() {
+            self.pending.clear();
+            return Err(Violation::InvalidUtf8);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn utf8_is_validated_across_frames() {
+        let mut v = Utf8Validator::default();
+        let text = "héllo".as_bytes();
+        assert_eq!(v.check(&text[..2]), Ok(()));
+        assert_eq!(v.check(&text[2..]), Ok(()));
+        assert_eq!(v.check_end(), Ok(()));
+
+        assert_eq!(v.check(&text[..2]), Ok(()));
+        assert_eq!(v.check_end(), Err(Violation::InvalidUtf8));
+
+        assert_eq!(v.check(&[0xFF]), Err(Violation::InvalidUtf8));
+    }
+
+    #[test]
+    fn close_reason_is_validated() {
+        let v = FrameValidator;
+        assert_eq!(v.check_close_reason(b"bye"), Ok(()));
+        assert_eq!(v.check_close_reason(b""), Ok(()));
+        assert_eq!(v.check_close_reason(&[0xFF]), Err(Violation::InvalidUtf8));
+    }
+}
@@ -0,0 +1,170 @@
+use crate::{Error, ErrorKind, Result};
+use async_std::channel::{self, Receiver, Sender};
+use async_std::io::{ReadExt, WriteExt};
+use async_std::net::TcpStream;
+use bytecodec::bytes::{BytesEncoder, RemainingBytesDecoder};
+use bytecodec::{DecodeExt, EncodeExt};
+use httpcodec::{
+    BodyDecoder, BodyEncoder, HeaderField, HttpVersion, Method, Request, RequestEncoder,
+    RequestTarget, ResponseDecoder,
+};
+use std::net::SocketAddr;
+use std::time::Duration;
+use trackable::error::ErrorKindExt;
+
+const QUEUE_SIZE: usize = 1024;
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Summary of a closed channel.
+#[derive(Debug, Clone)]
+pub struct CloseEvent {
+    pub client_addr: Option<SocketAddr>,
+    pub backend_addr: SocketAddr,
+    pub close_code: Option<u16>,
+    pub bytes: u64,
+    pub duration: Duration,
+}
+impl CloseEvent {
+    fn to_json(&self) -> String {
+        format!(
+            r#"{{"client":{},"backend":"{}","close_code":{},"bytes":{},"duration_ms":{}}}"#,
+            self.client_addr
+                .map_or_else(|| "null".to_owned(), |a| format!("\"{}\"", a)),
+            self.backend_addr,
+            self.close_code
+                .map_or_else(|| "null".to_owned(), |c| c.to_string()),
+            self.bytes,
+            self.duration.as_millis()
+        )
+    }
+}
+
+/// Handle for posting close events to an HTTP webhook.
+///
+/// Events are queued and posted one by one by a background task.
+/// If the queue is full, new events are dropped.
+#[derive(Debug, Clone)]
+pub struct CloseWebhook {
+    tx: Sender<CloseEvent>,
+}
+impl CloseWebhook {
+    /// Makes a new `CloseWebhook` instance that posts events to `url` (`http://HOST[:PORT][/PATH]`).
+    pub fn new(url: &str) -> Result<Self> {
+        let target = track!(WebhookTarget::parse(url))?;
+        let (tx, rx) = channel::bounded(QUEUE_SIZE);
+        async_std::task::spawn(run_worker(target, rx));
+        Ok(CloseWebhook { tx })
+    }
+
+    pub fn notify(&self, event: CloseEvent) {
+        if self.tx.try_send(event).is_err() {
+            log::warn!("Close webhook queue is full; the event is dropped");
+        }
+    }
+}
+
+#[derive(Debug)]
+struct WebhookTarget {
+    authority: String,
+    addr: String,
+    path: String,
+}
+impl WebhookTarget {
+    fn parse(url: &str) -> Result<Self> {
+        let rest = track_assert_some!(url.strip_prefix("http://"), ErrorKind::InvalidInput; url);
+        let (authority, path) = match rest.find('/') {
+            Some(i) => (&rest[..i], &rest[i..]),
+            None => (rest, "/"),
+        };
+        track_assert!(!authority.is_empty(), ErrorKind::InvalidInput; url);
+        track_assert!(
+            authority.bytes().all(|b| b.is_ascii_graphic()),
+            ErrorKind::InvalidInput; url
+        );
+        let has_port = authority
+            .rsplit_once(':')
+            .is_some_and(|(_, port)| !port.ends_with(']'));
+        let addr = if has_port {
+            authority.to_owned()
+        } else {
+            format!("{}:80", authority)
+        };
+        track!(RequestTarget::new(path).map_err(|e| ErrorKind::InvalidInput.takes_over(e)); url)?;
+        Ok(WebhookTarget {
+            authority: authority.to_owned(),
+            addr,
+            path: path.to_owned(),
+        })
+    }
+}
+
+async fn run_worker(target: WebhookTarget, rx: Receiver<CloseEvent>) {
+    while let Ok(event) = rx.recv().await {
+        match async_std::io::timeout(TIMEOUT, post(&target, &event)).await {
+            Err(e) => log::warn!("Cannot post a close event to the webhook: {}", e),
+            Ok(status) if !(200..300).contains(&status) => {
+                log::warn!("Close webhook responded with status {}", status);
+            }
+            Ok(_) => log::debug!("Posted a close event to the webhook: {:?}", event),
+        }
+    }
+}
+
+async fn post(target: &WebhookTarget, event: &CloseEvent) -> std::io::Result<u16> {
+    let request = track!(build_request(target, event)).map_err(std::io::Error::other)?;
+    let mut stream = TcpStream::connect(&target.addr).await?;
+    stream.write_all(&request).await?;
+
+    let mut buf = Vec::new();
+    stream.read_to_end(&mut buf).await?;
+    let response = ResponseDecoder::<BodyDecoder<RemainingBytesDecoder>>::default()
+        .decode_from_bytes(&buf)
+        .map_err(std::io::Error::other)?;
+    Ok(response.status_code().as_u16())
+}
+
+fn build_request(target: &WebhookTarget, event: &CloseEvent) -> Result<Vec<u8>> {
+    let method = track!(Method::new("POST").map_err(Error::from))?;
+    let request_target = track!(RequestTarget::new(&target.path).map_err(Error::from))?;
+    let mut request = Request::new(
+        method,
+        request_target,
+        HttpVersion::V1_1,
+        event.to_json().into_bytes(),
+    );
+    unsafe {
+        // The authority has been validated as part of the URL
+        request
+            .header_mut()
+            .add_field(HeaderField::new_unchecked("Host", &target.authority))
+            .add_field(HeaderField::new_unchecked(
+                "Content-Type",
+                "application/json",
+            ))
+            .add_field(HeaderField::new_unchecked("Connection", "close"));
+    }
+    let bytes = track!(RequestEncoder::new(BodyEncoder::new(BytesEncoder::new()))
+        .encode_into_bytes(request)
+        .map_err(Error::from))?;
+    Ok(bytes)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn webhook_target_works() {
+        let target = WebhookTarget::parse("http://example.com/hooks/close").unwrap();
+        assert_eq!(target.authority, "example.com");
+        assert_eq!(target.addr, "example.com:80");
+        assert_eq!(target.path, "/hooks/close");
+
+        let target = WebhookTarget::parse("http://[::1]:8080").unwrap();
+        assert_eq!(target.addr, "[::1]:8080");
+        assert_eq!(target.path, "/");
+
+        assert!(WebhookTarget::parse("https://example.com/").is_err());
+        assert!(WebhookTarget::parse("http:///").is_err());
+    }
+}